@@ -0,0 +1,60 @@
+/*
+ * Audit log of privileged commands
+ *
+ */
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use snafu::ResultExt;
+use crate::message::{Io, JsonEncode, Result};
+
+#[derive(Serialize)]
+pub struct AuditEntry<'a> {
+    pub timestamp: u64,
+    pub nick: &'a str,
+    pub hostmask: &'a str,
+    pub account: Option<&'a str>,
+    pub command: &'a str,
+    pub arguments: &'a str,
+    // each reply, or the error the command failed with
+    pub result: Vec<String>,
+}
+
+// append an entry to the log as a line of JSON
+pub fn append(path: &Path, entry: &AuditEntry) -> Result<()> {
+    let mut line = serde_json::to_string(entry).context(JsonEncode)?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path).context(Io)?;
+    file.write_all(line.as_bytes()).context(Io)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append() {
+        let path = std::env::temp_dir().join(format!("youtube-irc-bot-audit-{}.jsonl", std::process::id()));
+        let entry = AuditEntry {
+            timestamp: 1577836800,
+            nick: "nick",
+            hostmask: "nick!user@host",
+            account: None,
+            command: "!abort",
+            arguments: "UCsT0YIqwnpJCM-mx7-gSA4Q",
+            result: vec!["error: No task running for UCsT0YIqwnpJCM-mx7-gSA4Q".to_string()],
+        };
+        append(&path, &entry).unwrap();
+        append(&path, &AuditEntry { account: Some("acct"), command: "!stopscripts", arguments: "", ..entry }).unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            r#"{"timestamp":1577836800,"nick":"nick","hostmask":"nick!user@host","account":null,"command":"!abort","arguments":"UCsT0YIqwnpJCM-mx7-gSA4Q","result":["error: No task running for UCsT0YIqwnpJCM-mx7-gSA4Q"]}"#
+        );
+        assert!(lines[1].contains(r#""account":"acct","command":"!stopscripts""#));
+    }
+}
@@ -5,14 +5,17 @@
 use std::fs;
 use std::fs::File;
 use std::io::Write;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use toml;
 use std::path::{Path, PathBuf};
 use irc::client::data::Config as IrcConfig;
 use std::fmt;
 use directories::{ProjectDirs, BaseDirs};
-use snafu::ResultExt;
-use crate::message::{Result, TomlEncode, TomlDecode, Io};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use snafu::{ensure, ResultExt};
+use crate::message::{Result, TomlEncode, TomlDecode, Io, InvalidConfig};
+use crate::state::State;
 
 #[derive(Debug, Deserialize, Default)]
 pub struct Args {
@@ -32,23 +35,93 @@ pub enum HighlightMode {
     BoldItalic,
 }
 
+// How replies are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplyMode {
+    Privmsg,
+    Notice,
+}
+
+// What a user may do, from least to most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Role {
+    Denied,
+    User,
+    Trusted,
+    Admin,
+}
+
+// a single [connection] table, as in older configurations, or several
+// [[connection]] blocks
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<IrcConfig>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Box<IrcConfig>),
+        Many(Vec<IrcConfig>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(config) => vec![*config],
+        OneOrMany::Many(configs) => configs,
+    })
+}
+
+mod optional_regex {
+    use regex::Regex;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(regex: &Option<Regex>, serializer: S) -> Result<S::Ok, S::Error> {
+        regex.as_ref().map(Regex::as_str).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(pattern) => Regex::new(&pattern).map(Some).map_err(D::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
 // serde structures defining the configuration file structure
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 pub struct Conf {
+    // first, so that an emptied list is written as `deny = []` ahead of the
+    // tables rather than dropped, which would bring back the default
+    pub deny: Vec<DenyRule>,
     pub features: Features,
     #[serde(rename = "parameters")]
     pub params: Parameters,
-    #[serde(rename = "connection")]
-    pub client: IrcConfig,
+    // the bot connects to every network in a [[connection]] block
+    #[serde(rename = "connection", deserialize_with = "one_or_many")]
+    pub clients: Vec<IrcConfig>,
     pub user_limits: HashMap<String, usize>,
     pub user_highlights: HashMap<String, HighlightMode>,
+    // override features.send_notice for a user, keyed like user_highlights,
+    // or for a command such as "!status"; the user's choice wins
+    pub user_reply_modes: HashMap<String, ReplyMode>,
+    pub command_reply_modes: HashMap<String, ReplyMode>,
+    // command channels in addition to, or settings for, command_channel
+    pub channels: BTreeMap<String, ChannelSettings>,
+    pub profiles: BTreeMap<String, Profile>,
+    pub roles: Roles,
+    pub channel_modes: ChannelModes,
+    pub rate_limits: RateLimits,
+    pub blocklist: Blocklist,
+    // none by default; see the commented example in example.config.toml
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub moderation: Vec<ModerationRule>,
+    pub watchdog: Watchdog,
+    pub abort: Abort,
 }
 
 #[derive(Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Features {
+    // keep replies to a channel from highlighting anyone mentioned in them
+    // other than the user they're addressed to
     pub mask_highlights: bool,
+    // reply with NOTICE instead of PRIVMSG
     pub send_notice: bool,
 }
 
@@ -56,14 +129,254 @@ pub struct Features {
 #[serde(default)]
 pub struct Parameters {
     pub task_limit: usize,
+    pub video_task_limit: usize,
+    // seconds between attempts to start queued tasks
+    pub queue_interval: u64,
     pub command_channel: String,
+    pub default_profile: String,
+    // role of users not listed in [roles]
+    pub default_role: Role,
+    // key user_limits, user_highlights and task ownership by services
+    // account instead of nick; users who aren't logged in get the defaults
+    pub key_by_account: bool,
+    // archive requests from users below the Trusted role wait for a
+    // trusted user to `!approve` them
+    pub require_approval: bool,
+    // what it takes to use privileged commands in private messages
+    pub private_role: Role,
+    pub private_require_account: bool,
+    // replies too long for one IRC line are split into at most this many
+    // messages, the last ending in "…and N more words" for what was left out
+    pub max_reply_lines: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplyStyle {
+    // address replies to the requester, as in "nick: reply"
+    Highlight,
+    // send replies as they are
+    Plain,
+}
+
+// Settings of a command channel; unset limits and profile fall back to
+// [parameters]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelSettings {
+    // commands accepted in the channel, e.g. ["!status", "!a"]; all if empty
+    pub commands: Vec<String>,
+    pub task_limit: Option<usize>,
+    pub video_task_limit: Option<usize>,
+    pub default_profile: Option<String>,
+    pub reply_style: ReplyStyle,
+}
+
+// for the main command channel and private messages
+static DEFAULT_CHANNEL_SETTINGS: ChannelSettings = ChannelSettings {
+    commands: Vec::new(),
+    task_limit: None,
+    video_task_limit: None,
+    default_profile: None,
+    reply_style: ReplyStyle::Highlight,
+};
+
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        Self {
+            commands: vec![],
+            task_limit: None,
+            video_task_limit: None,
+            default_profile: None,
+            reply_style: ReplyStyle::Highlight,
+        }
+    }
+}
+
+// Users are matched by `nick!user@host` glob patterns or services account
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoleMembers {
+    pub hostmasks: Vec<String>,
+    pub accounts: Vec<String>,
+}
+
+// Membership of roles other than the default one. Being denied overrides
+// any other role.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Roles {
+    pub admin: RoleMembers,
+    pub trusted: RoleMembers,
+    pub denied: RoleMembers,
+}
+
+// Users matching `pattern`, a `nick!user@host` glob, can't use privileged
+// commands and are told `reply` instead, if it isn't empty
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DenyRule {
+    pub pattern: String,
+    pub reply: String,
+}
+
+// Channel IDs, folders, playlist IDs and video IDs that must never be
+// archived, and what requesters are told instead
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Blocklist {
+    pub ids: Vec<String>,
+    pub message: String,
+}
+
+impl Default for Blocklist {
+    fn default() -> Self {
+        Self {
+            ids: vec![],
+            message: "that can't be archived here".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModerationAction {
+    // kick the user from the channel, with `reason`
+    Kick,
+    // quiet the user's host in the channel with mode +q, on servers where
+    // CHANMODES lists q as a list mode (e.g. charybdis and solanum); elsewhere
+    // q is usually the owner prefix, and the rule does nothing
+    Quiet,
+    // reply with `reason`
+    Reply,
+    // do nothing
+    Ignore,
+}
+
+// A rule for messages in command channels, matching when all of its set
+// conditions match: `nick` and `hostmask` are globs, `account` a services
+// account and `message` a regex. Only the first matching rule applies, and
+// the message isn't handled as a command.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ModerationRule {
+    pub nick: Option<String>,
+    pub hostmask: Option<String>,
+    pub account: Option<String>,
+    // compiled when the configuration is loaded, which fails if it's invalid
+    #[serde(default, with = "optional_regex")]
+    pub message: Option<Regex>,
+    pub action: ModerationAction,
+    #[serde(default)]
+    pub reason: String,
+}
+
+// Channel prefix mode needed in the channel a command is sent to (or in
+// command_channel, for private messages) for reporting and for privileged
+// commands, e.g. "+" for voice or "@" for op; empty for none.
+// Higher prefix modes also qualify, and admins are exempt.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelModes {
+    pub reporting: String,
+    pub privileged: String,
+}
+
+// A token bucket allowing `burst` commands at once, refilled by one every
+// `interval` seconds; a burst of 0 disables the limit
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub interval: u64,
+}
+
+// Rate limits per user for reporting and for privileged commands; admins
+// are exempt
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub reporting: RateLimit,
+    pub privileged: RateLimit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            reporting: RateLimit { burst: 5, interval: 10 },
+            privileged: RateLimit { burst: 3, interval: 30 },
+        }
+    }
+}
+
+// Checks for tasks whose stash hasn't changed for `stall_timeout` seconds;
+// a timeout of 0 disables the watchdog
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Watchdog {
+    pub interval: u64,
+    pub stall_timeout: u64,
+    pub abort_stalled: bool,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self {
+            interval: 600,
+            stall_timeout: 86400,
+            abort_stalled: false,
+        }
+    }
+}
+
+// Seconds to wait for a task to exit after C-c, and then after SIGTERM,
+// before killing its tmux session
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Abort {
+    pub interrupt_timeout: u64,
+    pub terminate_timeout: u64,
+}
+
+impl Default for Abort {
+    fn default() -> Self {
+        Self {
+            interrupt_timeout: 10,
+            terminate_timeout: 5,
+        }
+    }
+}
+
+// Commands used to grab with a profile. The first element of `video` and
+// `channel` is the program to run, the rest are its arguments, in which
+// `{folder}`, `{url}` and `{limit}` are substituted.
+#[derive(Serialize, Deserialize)]
+pub struct Profile {
+    pub video: Vec<String>,
+    pub channel: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+impl Profile {
+    fn new(video_command: &str, channel_command: &str) -> Self {
+        Self {
+            video: vec![video_command.to_string(), "{folder}".to_string(), "{url}".to_string()],
+            channel: vec![channel_command.to_string(), "{folder}".to_string(), "{limit}".to_string()],
+            env: BTreeMap::new(),
+        }
+    }
 }
 
 impl Default for Parameters {
     fn default() -> Self {
         Self {
             task_limit: 34,
+            video_task_limit: 10,
+            queue_interval: 60,
             command_channel: "#youtubearchive".to_string(),
+            default_profile: "default".to_string(),
+            default_role: Role::User,
+            key_by_account: false,
+            require_approval: false,
+            private_role: Role::Trusted,
+            private_require_account: true,
+            max_reply_lines: 4,
         }
     }
 }
@@ -73,11 +386,31 @@ impl Conf {
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
         let conf = fs::read_to_string(path.as_ref()).context(Io)?;
         let conf: Conf = toml::de::from_str(&conf).context(TomlDecode)?;
+        conf.validate()?;
         Ok(conf)
     }
 
+    // reject settings that parse but can't work
+    fn validate(&self) -> Result<()> {
+        ensure!(self.params.queue_interval > 0, InvalidConfig { what: "queue_interval must be at least 1" });
+        ensure!(self.watchdog.interval > 0, InvalidConfig { what: "watchdog interval must be at least 1" });
+        Ok(())
+    }
+
+    pub fn is_command_channel(&self, channel: &str) -> bool {
+        channel.eq_ignore_ascii_case(&self.params.command_channel)
+            || self.channels.keys().any(|name| name.eq_ignore_ascii_case(channel))
+    }
+
+    // settings for commands sent to a channel, or in private if None
+    pub fn channel_settings(&self, channel: Option<&str>) -> &ChannelSettings {
+        channel
+            .and_then(|channel| self.channels.iter().find(|(name, _)| name.eq_ignore_ascii_case(channel)))
+            .map_or(&DEFAULT_CHANNEL_SETTINGS, |(_, settings)| settings)
+    }
+
     // write configuration to a file
-    pub fn write(self, path: impl AsRef<Path>) -> Result<()> {
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
         let mut file = File::create(path).context(Io)?;
         file.write_all(toml::ser::to_string(&self).context(TomlEncode)?.as_bytes()).context(Io)?;
         Ok(())
@@ -89,7 +422,7 @@ impl Default for Conf {
         Self {
             features: Features::default(),
             params: Parameters::default(),
-            client: IrcConfig {
+            clients: vec![IrcConfig {
                 nickname: Some("botnick".to_string()),
                 alt_nicks: Some(vec!["botnick_".to_string()]),
                 nick_password: Some("".to_string()),
@@ -109,9 +442,27 @@ impl Default for Conf {
                 max_messages_in_burst: Some(15),
                 should_ghost: Some(false),
                 ..IrcConfig::default()
-            },
+            }],
             user_limits: HashMap::new(),
             user_highlights: HashMap::new(),
+            user_reply_modes: HashMap::new(),
+            command_reply_modes: HashMap::new(),
+            channels: BTreeMap::new(),
+            profiles: vec![
+                ("default".to_string(), Profile::new("grab-youtube-video", "grab-youtube-channel")),
+                ("big".to_string(), Profile::new("grab-youtube-video-big-video", "grab-youtube-channel-big-videos")),
+            ].into_iter().collect(),
+            roles: Roles::default(),
+            channel_modes: ChannelModes::default(),
+            deny: vec![DenyRule {
+                pattern: "*!webchat@*".to_string(),
+                reply: "webchat users are not authorized; use any other IRC client, or ask someone else to do it".to_string(),
+            }],
+            moderation: vec![],
+            rate_limits: RateLimits::default(),
+            blocklist: Blocklist::default(),
+            watchdog: Watchdog::default(),
+            abort: Abort::default(),
         }
     }
 }
@@ -128,11 +479,15 @@ pub struct Rtd {
     pub conf: Conf,
     // command-line arguments
     pub args: Args,
+    // tasks and queue
+    pub state: State,
 }
 
 #[derive(Default)]
 pub struct Paths {
     pub conf: PathBuf,
+    // JSON lines log of privileged commands and refused ones
+    pub audit_log: PathBuf,
 }
 
 impl Rtd {
@@ -169,6 +524,9 @@ impl Rtd {
         // load config file
         rtd.conf = Conf::load(&rtd.paths.conf)?;
 
+        rtd.paths.audit_log = dirs.data_dir().join("audit.jsonl");
+        create_dir_if_missing(dirs.data_dir())?;
+
         Ok(rtd)
     }
 }
@@ -216,11 +574,43 @@ mod tests {
 
     #[test]
     fn example_conf_data_matches_generated_default_values() {
+        // the example also has commented out settings that aren't defaults
         let example = fs::read_to_string("example.config.toml").unwrap();
+        let example = example.lines().filter(|line| !line.starts_with('#')).map(|line| format!("{}\n", line)).collect::<String>();
         let default = toml::ser::to_string(&Conf::default()).unwrap();
         assert!(default == example);
     }
 
+    #[test]
+    fn test_validate() {
+        let mut conf = Conf::default();
+        assert!(conf.validate().is_ok());
+        conf.params.queue_interval = 0;
+        assert!(conf.validate().is_err());
+        conf.params.queue_interval = 60;
+        conf.watchdog.interval = 0;
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn test_invalid_moderation_regex() {
+        let rule = "[[moderation]]\naction = \"Kick\"\nmessage = ";
+        assert!(toml::de::from_str::<Conf>(&format!("{}\"^!a(rchive)? \"\n", rule)).is_ok());
+        assert!(toml::de::from_str::<Conf>(&format!("{}\"^!a(rchive \"\n", rule)).is_err());
+    }
+
+    #[test]
+    fn test_connections() {
+        let conf: Conf = toml::de::from_str("[connection]\nserver = \"irc.example.net\"\n").unwrap();
+        assert_eq!(conf.clients.len(), 1);
+        assert_eq!(conf.clients[0].server.as_deref(), Some("irc.example.net"));
+        let conf: Conf = toml::de::from_str(
+            "[[connection]]\nserver = \"irc.example.net\"\n[[connection]]\nserver = \"irc.example.org\"\n"
+        ).unwrap();
+        assert_eq!(conf.clients.len(), 2);
+        assert_eq!(conf.clients[1].server.as_deref(), Some("irc.example.org"));
+    }
+
     #[test]
     fn test_expand_tilde() {
         let homedir: PathBuf = BaseDirs::new()
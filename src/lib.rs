@@ -3,5 +3,11 @@ extern crate serde_derive;
 
 #[macro_use]
 mod macros;
+pub mod audit;
 pub mod config;
 pub mod message;
+pub mod moderation;
+pub mod options;
+pub mod state;
+pub mod tmux;
+pub mod users;
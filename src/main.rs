@@ -1,13 +1,19 @@
 extern crate youtube_irc_bot;
 
 use irc::client::prelude::*;
+use irc::error::IrcError;
+use std::cell::RefCell;
 use std::process;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
 use structopt::StructOpt;
+use tokio_core::reactor::Interval;
 
 use youtube_irc_bot::config::Rtd;
 use youtube_irc_bot::config::Args;
-use youtube_irc_bot::message::handle_message;
+use youtube_irc_bot::message::{check_stalled, handle_message, process_aborts, process_queue};
+use youtube_irc_bot::users::{ACCOUNT_CAPABILITIES, PREFIX_CAPABILITIES};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "youtube-irc-bot")]
@@ -44,26 +50,72 @@ fn main() {
     });
 
     println!("Using configuration: {}", rtd.paths.conf.display());
+    println!("Audit log: {}", rtd.paths.audit_log.display());
     if rtd.args.flag_verbose {
         println!("\n[features]\n{}", rtd.conf.features);
         println!("[parameters]\n{}", rtd.conf.params);
     }
 
-    // create IRC reactor
+    // create IRC reactor, with a client for each network
     let mut reactor = IrcReactor::new().unwrap();
-    let client = reactor
-        .prepare_client_and_connect(&rtd.conf.client)
-        .unwrap_or_else(|err| {
-        eprintln!("IRC prepare error: {}", err);
+    if rtd.conf.clients.is_empty() {
+        eprintln!("No [[connection]] configured");
         process::exit(1);
-    });
-    client.identify().unwrap();
+    }
+    let clients = rtd.conf.clients
+        .iter()
+        .map(|config| {
+            let client = reactor
+                .prepare_client_and_connect(config)
+                .unwrap_or_else(|err| {
+                eprintln!("IRC prepare error: {}", err);
+                process::exit(1);
+            });
+            // learn the services accounts of users, for roles and key_by_account
+            client.send_cap_req(&ACCOUNT_CAPABILITIES).unwrap();
+            // and every prefix mode they hold, for channel_modes
+            client.send_cap_req(&PREFIX_CAPABILITIES).unwrap();
+            client.identify().unwrap();
+            client
+        })
+        .collect::<Vec<_>>();
 
-    // register handler
-    reactor.register_client_with_handler(client, move |client, message| {
-        let _ = handle_message(client, &message, &rtd);
+    // register handlers, all sharing the same state
+    let queue_interval = Duration::from_secs(rtd.conf.params.queue_interval);
+    let watchdog_interval = Duration::from_secs(rtd.conf.watchdog.interval);
+    let rtd = Rc::new(RefCell::new(rtd));
+    for (network, client) in clients.iter().enumerate() {
+        let handler_rtd = Rc::clone(&rtd);
+        reactor.register_client_with_handler(client.clone(), move |client, message| {
+            let _ = handle_message(client, network, &message, &mut handler_rtd.borrow_mut());
+            Ok(())
+        });
+    }
+
+    // periodically start queued tasks once there is room for them
+    let queue_clients = clients.clone();
+    let queue_rtd = Rc::clone(&rtd);
+    let interval = Interval::new(queue_interval, &reactor.inner_handle()).unwrap();
+    reactor.register_future(interval.for_each(move |()| {
+        let _ = process_queue(&queue_clients, &mut queue_rtd.borrow_mut());
         Ok(())
-    });
+    }).map_err(IrcError::from));
+
+    // escalate aborts of tasks that don't exit, without blocking the reactor
+    let abort_clients = clients.clone();
+    let abort_rtd = Rc::clone(&rtd);
+    let interval = Interval::new(Duration::from_secs(1), &reactor.inner_handle()).unwrap();
+    reactor.register_future(interval.for_each(move |()| {
+        let _ = process_aborts(&abort_clients, &mut abort_rtd.borrow_mut());
+        Ok(())
+    }).map_err(IrcError::from));
+
+    // periodically look for tasks that stopped making progress
+    let interval = Interval::new(watchdog_interval, &reactor.inner_handle()).unwrap();
+    reactor.register_future(interval.for_each(move |()| {
+        let _ = check_stalled(&clients, &mut rtd.borrow_mut());
+        Ok(())
+    }).map_err(IrcError::from));
 
     reactor.run().unwrap_or_else(|err| {
         eprintln!("IRC client error: {}", err);
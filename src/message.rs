@@ -1,24 +1,29 @@
 use irc::client::prelude::*;
 use std::str;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use snafu::{ensure, ResultExt, Snafu, Backtrace};
 use ::phf::{Map, phf_map};
 
-use super::config::{Rtd, HighlightMode};
-
-enum VideoSize {
-    Normal,
-    VeryBig
-}
+use super::audit::{self, AuditEntry};
+use super::config::{Rtd, DenyRule, HighlightMode, ModerationAction, Profile, ReplyMode, ReplyStyle, Role, RoleMembers};
+use super::moderation;
+use super::options::{GrabOptions, options_help, parse_grab_command};
+use super::state::{AbortJob, AbortOutcome, AbortStage, GrabRequest, Identity, PendingAbort, TaskProgress, TaskRecord, TokenBucket};
+use super::users::Users;
+use super::tmux::{self, DownloaderSession, get_downloader_sessions};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub(crate)")]
 pub enum Error {
     TomlEncode { source: toml::ser::Error },
     TomlDecode { source: toml::de::Error },
+    #[snafu(display("Invalid configuration: {}", what))]
+    InvalidConfig { what: String },
+    JsonEncode { source: serde_json::Error },
     Io { source: std::io::Error, backtrace: Backtrace },
     Utf8 { source: std::str::Utf8Error, backtrace: Backtrace },
     UrlTooLong,
@@ -26,6 +31,9 @@ pub enum Error {
     UnsupportedUrl { url: String },
     #[snafu(display("Not authorized"))]
     NotAuthorized,
+    // refused by a deny rule with its own reply, which is sent as it is
+    #[snafu(display("{}", reply))]
+    Denied { reply: String },
     #[snafu(display("Could not get channel identifier"))]
     CouldNotGetChannelIdentifier,
     #[snafu(display("Invalid task name: {}", task))]
@@ -36,6 +44,52 @@ pub enum Error {
     ErrorListingFiles { folder: String },
     #[snafu(display("Internal error creating folder {}", folder))]
     ErrorCreatingFolder { folder: String },
+    #[snafu(display("Unknown option {}", option))]
+    UnknownOption { option: String },
+    #[snafu(display("Missing value for {}", option))]
+    MissingOptionValue { option: String },
+    #[snafu(display("Invalid value {} for {}, expected {}", value, option, expected))]
+    InvalidOptionValue { option: String, value: String, expected: String },
+    #[snafu(display("{} can't be used for {}", option, what))]
+    OptionNotApplicable { option: String, what: String },
+    #[snafu(display("Unexpected arguments: {}", what))]
+    UnexpectedArguments { what: String },
+    #[snafu(display("No task running for {}", task))]
+    NoSuchTask { task: String },
+    #[snafu(display("{} is still running after killing its session", task))]
+    AbortFailed { task: String },
+    #[snafu(display("{} is already being aborted", task))]
+    AlreadyAborting { task: String },
+    #[snafu(display("Could not {} {}", action, task))]
+    TaskControlFailed { action: String, task: String },
+    #[snafu(display("{} was started by {}; only they or an admin can do that", task, owner))]
+    NotTaskOwner { task: String, owner: String },
+    #[snafu(display("No earlier request for {} to retry", task))]
+    NoRequestHistory { task: String },
+    #[snafu(display("Only users with the {:?} role can do that", role))]
+    RoleRequired { role: Role },
+    #[snafu(display("Only users with the {:?} role can do that in private messages", role))]
+    PrivateRoleRequired { role: Role },
+    #[snafu(display("You need to be logged in to services to do that in private messages"))]
+    AccountRequired,
+    #[snafu(display("You need {} or higher in {} to do that", mode, channel))]
+    ChannelModeRequired { mode: char, channel: String },
+    #[snafu(display("No deny pattern {}", pattern))]
+    NoSuchDenyPattern { pattern: String },
+    #[snafu(display("Please slow down; try again in {}", wait))]
+    RateLimited { wait: String },
+    #[snafu(display("Can't archive {}: {}", url, message))]
+    Blocked { url: String, message: String },
+    #[snafu(display("{} is not on the blocklist", id))]
+    NotBlocked { id: String },
+    #[snafu(display("No request #{} is waiting for approval", id))]
+    NoPendingRequest { id: String },
+    #[snafu(display("Nothing to confirm"))]
+    NothingToConfirm,
+    #[snafu(display("Unknown profile {}", profile))]
+    UnknownProfile { profile: String },
+    #[snafu(display("Profile {} has no {} command", profile, kind))]
+    EmptyProfileCommand { profile: String, kind: String },
 }
 
 pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
@@ -68,7 +122,7 @@ fn extract_channel_id(page_contents: &str) -> Result<String> {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FetchType {
     User,
     Channel,
@@ -76,11 +130,13 @@ pub enum FetchType {
     Video,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CanonicalizedYoutubeDescriptor {
     id: String,
     folder: String,
     kind: FetchType,
+    // ID of the channel the content belongs to, when known
+    channel: Option<String>,
 }
 
 impl CanonicalizedYoutubeDescriptor {
@@ -96,6 +152,17 @@ impl CanonicalizedYoutubeDescriptor {
     pub fn folder(&self) -> String {
         self.folder.clone()
     }
+
+    pub fn kind(&self) -> FetchType {
+        self.kind
+    }
+
+    // the blocklist entry matching this content, if any
+    pub fn blocked_by<'a>(&self, blocklist: &'a [String]) -> Option<&'a String> {
+        blocklist.iter().find(|entry| {
+            **entry == self.id || **entry == self.folder || Some(*entry) == self.channel.as_ref()
+        })
+    }
 }
 
 #[allow(clippy::let_and_return)]
@@ -168,11 +235,11 @@ impl YoutubeDescriptor {
             YoutubeDescriptor::Video(id) => {
                 let contents = contents_for_url(&self.to_url())?;
                 let channel_id = extract_channel_id(&contents)?;
-                let folder = YoutubeDescriptor::Channel(channel_id).canonicalize()?.folder();
-                CanonicalizedYoutubeDescriptor { kind: FetchType::Video, id: id.clone(), folder }
+                let folder = YoutubeDescriptor::Channel(channel_id.clone()).canonicalize()?.folder();
+                CanonicalizedYoutubeDescriptor { kind: FetchType::Video, id: id.clone(), folder, channel: Some(channel_id) }
             },
             YoutubeDescriptor::Playlist(id) => {
-                CanonicalizedYoutubeDescriptor { kind: FetchType::Playlist, id: id.clone(), folder: id.clone() }
+                CanonicalizedYoutubeDescriptor { kind: FetchType::Playlist, id: id.clone(), folder: id.clone(), channel: None }
             },
             YoutubeDescriptor::Channel(_) | YoutubeDescriptor::User(_) => {
                 let contents = contents_for_url(&self.to_url())?;
@@ -180,14 +247,15 @@ impl YoutubeDescriptor {
                 match username {
                     None => {
                         let channel_id = extract_channel_id(&contents)?;
-                        CanonicalizedYoutubeDescriptor { kind: FetchType::Channel, id: channel_id.clone(), folder: channel_id }
+                        CanonicalizedYoutubeDescriptor { kind: FetchType::Channel, id: channel_id.clone(), folder: channel_id.clone(), channel: Some(channel_id) }
                     }
                     Some(username) => {
                         let mut folder = username.clone();
                         if let Some(&custom_folder) = FOLDER_EXCEPTIONS.get(username.as_str()) {
                             folder = custom_folder.to_string();
                         }
-                        CanonicalizedYoutubeDescriptor { kind: FetchType::User, id: username, folder }
+                        let channel = extract_channel_id(&contents).ok();
+                        CanonicalizedYoutubeDescriptor { kind: FetchType::User, id: username, folder, channel }
                     }
                 }
             },
@@ -195,42 +263,236 @@ impl YoutubeDescriptor {
     }
 }
 
-fn archive(original_url: &str, descriptor: &CanonicalizedYoutubeDescriptor, video_size: VideoSize, user: &str, rtd: &Rtd) -> Result<String> {
+// Look up the profile requested in the options, or the default one for
+// the channel the request was sent to
+fn profile_for<'a>(options: &GrabOptions, channel: Option<&str>, rtd: &'a Rtd) -> Result<(String, &'a Profile)> {
+    let name = options.profile.as_ref()
+        .or_else(|| rtd.conf.channel_settings(channel).default_profile.as_ref())
+        .unwrap_or(&rtd.conf.params.default_profile);
+    match rtd.conf.profiles.get(name) {
+        Some(profile) => Ok((name.clone(), profile)),
+        None => UnknownProfile { profile: name.as_str() }.fail(),
+    }
+}
+
+// Run the grab command of the selected profile, returning the profile's name
+fn start_grab(descriptor: &CanonicalizedYoutubeDescriptor, options: &GrabOptions, rtd: &Rtd) -> Result<String> {
+    let (name, profile) = profile_for(options, None, rtd)?;
     let folder = descriptor.folder();
+    let url = descriptor.to_url();
+    let videos_limit = options.limit.unwrap_or(999_999).to_string();
+    let (kind, template, extra_args) = match descriptor.kind {
+        FetchType::Video => ("video", &profile.video, vec![]),
+        FetchType::Channel | FetchType::User | FetchType::Playlist => ("channel", &profile.channel, options.extra_args()),
+    };
+    let argv = template
+        .iter()
+        .map(|arg| arg.replace("{folder}", &folder).replace("{url}", &url).replace("{limit}", &videos_limit))
+        .collect::<Vec<_>>();
+    let (command, args) = match argv.split_first() {
+        Some(split) => split,
+        None => return EmptyProfileCommand { profile: name, kind }.fail(),
+    };
+    let output = process::Command::new(command)
+        .args(args)
+        .args(extra_args)
+        .envs(&profile.env)
+        .output()
+        .context(Io)?;
+    let _ = str::from_utf8(&output.stdout).context(Utf8)?;
+    Ok(name)
+}
+
+// Videos count against their own limit, everything else against the
+// requester's task limit, as set for the channel it was requested in.
+// Returns why a task can't be started right now.
+fn task_limit_reason(kind: FetchType, owner: &Identity, channel: Option<&str>, sessions: &[DownloaderSession], rtd: &Rtd) -> Option<String> {
+    if kind == FetchType::Video {
+        let videos_limit = rtd.conf.channel_settings(channel).video_task_limit.unwrap_or(rtd.conf.params.video_task_limit);
+        if rtd.state.running_videos(sessions) >= videos_limit {
+            return Some(format!("too many videos are being grabbed (limit = {})", videos_limit));
+        }
+    } else {
+        let tasks_limit = limit_for_user(owner, channel, rtd);
+        if sessions.len() >= tasks_limit {
+            return Some(format!("too many tasks are running (your limit = {})", tasks_limit));
+        }
+    }
+    None
+}
+
+fn grabbing_message(original_url: &str, folder: &str) -> String {
+    format!("Grabbing {} -> {}; check {} later", original_url, folder, logs_url(folder))
+}
+
+fn new_request(url: &str, descriptor: &CanonicalizedYoutubeDescriptor, options: &GrabOptions, sender: &Sender) -> GrabRequest {
+    GrabRequest {
+        url: url.to_string(),
+        descriptor: descriptor.clone(),
+        options: options.clone(),
+        requester: sender.nick.to_string(),
+        approver: None,
+        owner: sender.identity.clone(),
+        approver_identity: None,
+        network: sender.network,
+        channel: sender.channel.map(String::from),
+    }
+}
+
+fn check_blocklist(request: &GrabRequest, rtd: &Rtd) -> Result<()> {
+    let blocklist = &rtd.conf.blocklist;
+    ensure!(request.descriptor.blocked_by(&blocklist.ids).is_none(), Blocked { url: &request.url, message: &blocklist.message });
+    Ok(())
+}
+
+fn archive(mut request: GrabRequest, rtd: &mut Rtd) -> Result<String> {
+    check_blocklist(&request, rtd)?;
+    let original_url = request.url.clone();
+    let folder = request.descriptor.folder();
     let sessions = get_downloader_sessions()?;
-    if let Some(_session) = sessions.iter().find(|session| session.identifier == folder) {
-        return Ok(format!("Can't archive {} because another task is running in the same folder {}", &original_url, &folder));
-    }
-    match descriptor.kind {
-        FetchType::Video => {
-            let command = match video_size {
-                VideoSize::Normal  => "grab-youtube-video",
-                VideoSize::VeryBig => "grab-youtube-video-big-video"
-            };
-            let output = process::Command::new(command)
-                .arg(&folder).arg(&descriptor.to_url())
-                .output()
-                .context(Io)?;
-            let _ = str::from_utf8(&output.stdout).context(Utf8)?;
+    rtd.state.forget_finished(&sessions);
+    if let Some(reason) = folder_conflict(&request, &sessions, rtd) {
+        return Ok(reason);
+    }
+    // remember the profile actually used, so a retry doesn't depend on the
+    // default profile at that time
+    let (profile, _) = profile_for(&request.options, request.channel.as_deref(), rtd)?;
+    request.options.profile = Some(profile);
+    rtd.state.history.insert(folder.clone(), request.clone());
+    if let Some(reason) = task_limit_reason(request.descriptor.kind, request.limited_as(), request.channel.as_deref(), &sessions, rtd) {
+        rtd.state.queue.push_back(request);
+        return Ok(format!("Queued {} -> {} at position {} because {}", original_url, folder, rtd.state.queue.len(), reason));
+    }
+    let profile = start_grab(&request.descriptor, &request.options, rtd)?;
+    rtd.state.tasks.insert(folder.clone(), TaskRecord::new(&request, profile));
+    Ok(grabbing_message(&original_url, &folder))
+}
+
+// Why a request can't be archived while another task uses its folder
+fn folder_conflict(request: &GrabRequest, sessions: &[DownloaderSession], rtd: &Rtd) -> Option<String> {
+    let folder = request.descriptor.folder();
+    if sessions.iter().any(|session| session.identifier == folder) {
+        return Some(format!("Can't archive {} because another task is running in the same folder {}", request.url, folder));
+    }
+    if rtd.state.queue.iter().any(|task| task.descriptor.folder() == folder) {
+        return Some(format!("Can't archive {} because another task for the same folder {} is already queued", request.url, folder));
+    }
+    None
+}
+
+// Archive a request right away, or keep it for approval if the sender
+// isn't trusted and approval is required
+fn submit(request: GrabRequest, sender: &Sender, rtd: &mut Rtd) -> Result<String> {
+    if !rtd.conf.params.require_approval || sender.role >= Role::Trusted {
+        return archive(request, rtd);
+    }
+    check_blocklist(&request, rtd)?;
+    let folder = request.descriptor.folder();
+    let pending = rtd.state.pending.iter().find(|(_, pending)| pending.descriptor.folder() == folder);
+    if let Some((id, _)) = pending {
+        return Ok(format!("A request for the same folder {} is already waiting for approval as #{}", folder, id));
+    }
+    rtd.state.next_pending_id += 1;
+    let id = rtd.state.next_pending_id;
+    let reply = format!("{} -> {} is waiting for approval by a trusted user as #{}", request.url, folder, id);
+    rtd.state.pending.insert(id, request);
+    Ok(reply)
+}
+
+// Run the latest request for a folder again, as `sender`
+fn retry(task: &str, sender: &Sender, rtd: &mut Rtd) -> Result<String> {
+    assert_valid_task_name(task)?;
+    let request = match rtd.state.history.get(task) {
+        Some(request) => new_request(&request.url, &request.descriptor, &request.options, sender),
+        None => return NoRequestHistory { task }.fail(),
+    };
+    submit(request, sender, rtd)
+}
+
+fn pending_id(id: &str, rtd: &Rtd) -> Result<u32> {
+    match id.trim_start_matches('#').parse::<u32>() {
+        Ok(id) if rtd.state.pending.contains_key(&id) => Ok(id),
+        _ => NoPendingRequest { id }.fail(),
+    }
+}
+
+// Archive a pending request on behalf of `sender`, crediting both them and
+// the original requester, who can both control the task. A request that
+// can't be archived yet stays pending.
+fn approve(id: &str, sender: &Sender, rtd: &mut Rtd) -> Result<String> {
+    let id = pending_id(id, rtd)?;
+    let sessions = get_downloader_sessions()?;
+    if let Some(reason) = folder_conflict(&rtd.state.pending[&id], &sessions, rtd) {
+        return Ok(format!("#{} is still waiting for approval: {}", id, reason));
+    }
+    let mut request = rtd.state.pending.remove(&id).unwrap();
+    let requester = request.requester.clone();
+    request.approver = Some(sender.nick.to_string());
+    request.approver_identity = Some(sender.identity.clone());
+    match archive(request.clone(), rtd) {
+        Ok(reply) => Ok(format!("Approved #{} from {}: {}", id, requester, reply)),
+        Err(err) => {
+            rtd.state.pending.insert(id, request);
+            Err(err)
         },
-        FetchType::Channel | FetchType::User | FetchType::Playlist => {
-            let tasks_limit = limit_for_user(user, rtd);
-            if sessions.len() >= tasks_limit {
-                return Ok(format!("Can't archive {} because too many tasks are running (your limit = {}), try again later", &original_url, tasks_limit));
-            }
-            let videos_limit = 999_999;
-            let command = match video_size {
-                VideoSize::Normal  => "grab-youtube-channel",
-                VideoSize::VeryBig => "grab-youtube-channel-big-videos"
-            };
-            let output = process::Command::new(command)
-                .arg(&folder).arg(videos_limit.to_string())
-                .output()
-                .context(Io)?;
-            let _ = str::from_utf8(&output.stdout).context(Utf8)?;
+    }
+}
+
+// `!reject <id> [reason]`
+fn reject(args: &str, rtd: &mut Rtd) -> Result<String> {
+    let args = args.trim();
+    let (id, reason) = args.split_once(' ').unwrap_or((args, ""));
+    let id = pending_id(id, rtd)?;
+    let request = rtd.state.pending.remove(&id).unwrap();
+    let mut reply = format!("Rejected #{} from {} for {}", id, request.requester, request.url);
+    if !reason.trim().is_empty() {
+        reply.push_str(&format!(": {}", reason.trim()));
+    }
+    Ok(reply)
+}
+
+fn get_pending(rtd: &Rtd) -> Result<String> {
+    if rtd.state.pending.is_empty() {
+        return Ok("No requests waiting for approval".to_string());
+    }
+    let entries = rtd.state.pending
+        .iter()
+        .map(|(id, request)| format!("#{} {} by {}", id, request.url, request.requester))
+        .collect::<Vec<_>>();
+    Ok(format!("Waiting for approval: {}", summarize_list(&entries, 10)))
+}
+
+// Start queued tasks that fit within the limits now, keeping the rest in
+// their original order
+pub fn process_queue(clients: &[IrcClient], rtd: &mut Rtd) -> Result<()> {
+    if rtd.state.queue.is_empty() {
+        return Ok(());
+    }
+    let mut sessions = get_downloader_sessions()?;
+    rtd.state.forget_finished(&sessions);
+    let queue = std::mem::take(&mut rtd.state.queue);
+    for task in queue {
+        // the blocklist may have changed since the task was queued
+        let blocklist = &rtd.conf.blocklist;
+        if task.descriptor.blocked_by(&blocklist.ids).is_some() {
+            let result = Blocked { url: &task.url, message: &blocklist.message }.fail();
+            reply_to_requester(clients, task.network, task.channel.as_deref(), &task.requester, result, rtd);
+            continue;
+        }
+        let folder = task.descriptor.folder();
+        let busy = sessions.iter().any(|session| session.identifier == folder);
+        if busy || task_limit_reason(task.descriptor.kind, task.limited_as(), task.channel.as_deref(), &sessions, rtd).is_some() {
+            rtd.state.queue.push_back(task);
+            continue;
         }
+        let result = start_grab(&task.descriptor, &task.options, rtd).map(|profile| {
+            rtd.state.tasks.insert(folder.clone(), TaskRecord::new(&task, profile));
+            sessions.push(DownloaderSession { identifier: folder.clone(), start_time: unix_time() });
+            grabbing_message(&task.url, &folder)
+        });
+        reply_to_requester(clients, task.network, task.channel.as_deref(), &task.requester, result, rtd);
     }
-    Ok(format!("Grabbing {} -> {}; check {} later", &original_url, &folder, logs_url(&folder)))
+    Ok(())
 }
 
 fn assert_valid_task_name(task: &str) -> Result<()> {
@@ -239,21 +501,252 @@ fn assert_valid_task_name(task: &str) -> Result<()> {
     Ok(())
 }
 
-fn abort(task: &str) -> Result<String> {
+// Interrupt tasks, leaving `process_aborts` to escalate to SIGTERM and then
+// to killing the sessions of those that don't exit in time. All tasks are
+// escalated together, so aborting many tasks takes no longer than aborting
+// one. Tasks that are already being aborted are left to their earlier job.
+fn start_abort(mut job: AbortJob, rtd: &mut Rtd) -> Result<()> {
+    job.running.retain(|task| !rtd.state.is_aborting(task));
+    for task in &job.running {
+        // a stopped task would only see the interrupt once continued
+        if rtd.state.paused.remove(task) {
+            let _ = tmux::signal(task, "CONT");
+        }
+        tmux::send_keys(task, "C-c")?;
+    }
+    if !job.running.is_empty() {
+        job.deadline = unix_time() + rtd.conf.abort.interrupt_timeout;
+        rtd.state.aborts.push(job);
+    }
+    Ok(())
+}
+
+// Escalate aborts whose tasks didn't exit in time, and report those that
+// are over
+pub fn process_aborts(clients: &[IrcClient], rtd: &mut Rtd) -> Result<()> {
+    if rtd.state.aborts.is_empty() {
+        return Ok(());
+    }
+    let now = unix_time();
+    let jobs = std::mem::take(&mut rtd.state.aborts);
+    for mut job in jobs {
+        let exited = match job.stage {
+            AbortStage::Interrupt => AbortOutcome::Interrupted,
+            AbortStage::Terminate => AbortOutcome::Terminated,
+            AbortStage::Kill      => AbortOutcome::Killed,
+        };
+        for task in std::mem::take(&mut job.running) {
+            if tmux::session_exists(&task).unwrap_or(true) {
+                job.running.push(task);
+            } else {
+                job.outcomes.push((task, exited));
+            }
+        }
+        if !job.running.is_empty() && now >= job.deadline {
+            match job.stage {
+                AbortStage::Interrupt => {
+                    for task in &job.running {
+                        // the session may exit on its own before the signal is sent
+                        let _ = tmux::signal(task, "TERM");
+                    }
+                    job.stage = AbortStage::Terminate;
+                    job.deadline = now + rtd.conf.abort.terminate_timeout;
+                },
+                AbortStage::Terminate => {
+                    for task in &job.running {
+                        let _ = tmux::kill_session(task);
+                    }
+                    job.stage = AbortStage::Kill;
+                    job.deadline = now + 1;
+                },
+                AbortStage::Kill => {
+                    let still_running = std::mem::take(&mut job.running);
+                    job.outcomes.extend(still_running.into_iter().map(|task| (task, AbortOutcome::StillRunning)));
+                },
+            }
+        }
+        if !job.running.is_empty() {
+            rtd.state.aborts.push(job);
+            continue;
+        }
+        let result = abort_result(&job, rtd).map(|reply| format!("{}{}", job.context, reply));
+        report_task(clients, job.network, job.channel.as_deref(), job.requester.as_deref(), result, rtd);
+    }
+    Ok(())
+}
+
+// what became of an abort once none of its tasks are running
+fn abort_result(job: &AbortJob, rtd: &Rtd) -> Result<String> {
+    if job.bulk {
+        return Ok(bulk_abort_summary(&job.outcomes));
+    }
+    let (task, outcome) = &job.outcomes[0];
+    match outcome {
+        AbortOutcome::Interrupted  => Ok(format!("Aborted {}", task)),
+        AbortOutcome::Terminated   => {
+            Ok(format!("Aborted {} with SIGTERM after it ignored C-c for {}s", task, rtd.conf.abort.interrupt_timeout))
+        },
+        AbortOutcome::Killed       => Ok(format!("Killed the session of {} after it ignored C-c and SIGTERM", task)),
+        AbortOutcome::StillRunning => AbortFailed { task }.fail(),
+    }
+}
+
+fn abort(task: &str, sender: &Sender, rtd: &mut Rtd) -> Result<String> {
     assert_valid_task_name(task)?;
-    let session = format!("YouTube-{}", task);
-    let _output = process::Command::new("tmux")
-        .arg("send-keys").arg("-t").arg(&session).arg("C-c")
-        .output()
-        .context(Io)?;
-    Ok(format!("Aborted {}", &task))
+    ensure!(tmux::session_exists(task)?, NoSuchTask { task });
+    ensure!(!rtd.state.is_aborting(task), AlreadyAborting { task });
+    let job = AbortJob::new(vec![task.to_string()], Some(sender.nick.to_string()), sender.network, sender.channel.map(String::from));
+    start_abort(job, rtd)?;
+    Ok(format!("Aborting {}; sent C-c", task))
 }
 
-fn limit_for_user(user: &str, rtd: &Rtd) -> usize {
+// Match shell-style wildcards, ignoring case: `*` matches any run of
+// characters and `?` any single character
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut re = String::from("(?i)\\A");
+    for c in pattern.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c   => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push_str("\\z");
+    Regex::new(&re).map(|re| re.is_match(text)).unwrap_or(false)
+}
+
+// "a, b, c and 2 more"
+fn summarize_list(items: &[String], max_items: usize) -> String {
+    if items.len() <= max_items {
+        return items.join(", ");
+    }
+    format!("{} and {} more", items[..max_items].join(", "), items.len() - max_items)
+}
+
+// how long a bulk abort waits for `!abort --confirm`
+const BULK_ABORT_CONFIRM_TIMEOUT: u64 = 120;
+
+// Select the tasks to abort and ask for confirmation before aborting them
+fn prepare_bulk_abort(sender: &Sender, selected: impl Fn(&DownloaderSession, Option<&TaskRecord>) -> bool, rtd: &mut Rtd) -> Result<String> {
+    require_role(sender, Role::Admin)?;
+    let sessions = get_downloader_sessions()?;
+    rtd.state.forget_finished(&sessions);
+    let tasks = sessions
+        .iter()
+        .filter(|session| selected(session, rtd.state.tasks.get(&session.identifier)))
+        .map(|session| session.identifier.clone())
+        .collect::<Vec<_>>();
+    if tasks.is_empty() {
+        return Ok("No running tasks match".to_string());
+    }
+    let reply = format!(
+        "This would abort {} tasks: {}; say !abort --confirm within {}s to proceed",
+        tasks.len(), summarize_list(&tasks, 10), BULK_ABORT_CONFIRM_TIMEOUT
+    );
+    let expires = unix_time() + BULK_ABORT_CONFIRM_TIMEOUT;
+    rtd.state.pending_aborts.insert((sender.network, sender.nick.to_lowercase()), PendingAbort { tasks, expires });
+    Ok(reply)
+}
+
+fn confirm_bulk_abort(sender: &Sender, rtd: &mut Rtd) -> Result<String> {
+    require_role(sender, Role::Admin)?;
+    let pending = match rtd.state.pending_aborts.remove(&(sender.network, sender.nick.to_lowercase())) {
+        Some(pending) if pending.expires >= unix_time() => pending,
+        _ => return NothingToConfirm.fail(),
+    };
+    let sessions = get_downloader_sessions()?;
+    rtd.state.forget_finished(&sessions);
+    let tasks = pending.tasks
+        .into_iter()
+        .filter(|task| sessions.iter().any(|session| &session.identifier == task))
+        .collect::<Vec<_>>();
+    if tasks.is_empty() {
+        return Ok("None of those tasks are still running".to_string());
+    }
+    let count = tasks.len();
+    let job = AbortJob { bulk: true, ..AbortJob::new(tasks, Some(sender.nick.to_string()), sender.network, sender.channel.map(String::from)) };
+    start_abort(job, rtd)?;
+    Ok(format!("Aborting {} tasks; sent C-c", count))
+}
+
+fn bulk_abort_summary(outcomes: &[(String, AbortOutcome)]) -> String {
+    let count = |outcome| outcomes.iter().filter(|(_, o)| *o == outcome).count();
+    let still_running = outcomes
+        .iter()
+        .filter(|(_, outcome)| *outcome == AbortOutcome::StillRunning)
+        .map(|(task, _)| task.clone())
+        .collect::<Vec<_>>();
+    let mut reply = format!(
+        "Aborted {} of {} tasks ({} with C-c, {} with SIGTERM, {} killed)",
+        outcomes.len() - still_running.len(), outcomes.len(),
+        count(AbortOutcome::Interrupted), count(AbortOutcome::Terminated), count(AbortOutcome::Killed)
+    );
+    if !still_running.is_empty() {
+        reply.push_str(&format!("; still running: {}", summarize_list(&still_running, 10)));
+    }
+    reply
+}
+
+// `!abort <task>`, or an admin-only bulk abort with `--all`, `--user NICK`
+// or `--match GLOB`, which has to be confirmed with `--confirm`
+fn abort_command(args: &str, sender: &Sender, rtd: &mut Rtd) -> Result<String> {
+    match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [task] if !task.starts_with("--") => {
+            check_task_owner(task, sender, rtd)?;
+            abort(task, sender, rtd)
+        },
+        ["--confirm"] => confirm_bulk_abort(sender, rtd),
+        ["--all"] => prepare_bulk_abort(sender, |_, _| true, rtd),
+        ["--user", nick] => prepare_bulk_abort(sender, |_, record| {
+            matches!(record, Some(record) if record.requester.eq_ignore_ascii_case(nick) || record.owner.name().eq_ignore_ascii_case(nick))
+        }, rtd),
+        ["--match", pattern] => prepare_bulk_abort(sender, |session, _| glob_match(pattern, &session.identifier), rtd),
+        _ => UnexpectedArguments { what: "expected <task>, --all, --user NICK, --match GLOB or --confirm" }.fail(),
+    }
+}
+
+// Only the requester of a task, whoever approved it or an admin may control
+// it. Tasks without a record (e.g. started before the bot was restarted)
+// have no known owner.
+fn check_task_owner(task: &str, sender: &Sender, rtd: &Rtd) -> Result<()> {
+    match rtd.state.tasks.get(task) {
+        Some(record) if !record.owner.matches(&sender.identity)
+            && !matches!(&record.approver_identity, Some(approver) if approver.matches(&sender.identity))
+            && sender.role < Role::Admin => {
+            NotTaskOwner { task, owner: record.requester.as_str() }.fail()
+        },
+        _ => Ok(()),
+    }
+}
+
+// Suspend or continue all processes of a running task
+fn set_paused(task: &str, paused: bool, rtd: &mut Rtd) -> Result<String> {
+    assert_valid_task_name(task)?;
+    let sessions = get_downloader_sessions()?;
+    rtd.state.forget_finished(&sessions);
+    ensure!(sessions.iter().any(|session| session.identifier == task), NoSuchTask { task });
+    if paused {
+        tmux::signal(task, "STOP")?;
+        rtd.state.paused.insert(task.to_string());
+        Ok(format!("Paused {}", task))
+    } else {
+        tmux::signal(task, "CONT")?;
+        rtd.state.paused.remove(task);
+        // don't count the time spent paused as a stall
+        rtd.state.progress.remove(task);
+        Ok(format!("Resumed {}", task))
+    }
+}
+
+fn limit_for_user(owner: &Identity, channel: Option<&str>, rtd: &Rtd) -> usize {
     let user_limits = &rtd.conf.user_limits;
-    match user_limits.get(user) {
-        None         => rtd.conf.params.task_limit,
-        Some(&limit) => limit
+    let limit = match owner {
+        Identity::Nick(..) if rtd.conf.params.key_by_account => None,
+        owner => user_limits.iter().find(|(name, _)| name.eq_ignore_ascii_case(owner.name())),
+    };
+    match limit {
+        None              => rtd.conf.channel_settings(channel).task_limit.unwrap_or(rtd.conf.params.task_limit),
+        Some((_, &limit)) => limit
     }
 }
 
@@ -299,10 +792,8 @@ fn get_file_listing(folder: &str) -> Result<Vec<String>> {
     Ok(stdout_utf8.lines().map(String::from).collect())
 }
 
-#[derive(Debug)]
-struct DownloaderSession {
-    identifier: String,
-    start_time: u64,
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
 }
 
 fn stop_scripts() -> Result<String> {
@@ -315,50 +806,192 @@ fn cont_scripts() -> Result<String> {
     Ok("Continued all scripts".to_string())
 }
 
-fn get_status(rtd: &Rtd) -> Result<String> {
+// Overall status, with the limits that apply in `channel`
+fn get_status(channel: Option<&str>, rtd: &mut Rtd) -> Result<String> {
     let sessions = get_downloader_sessions()?;
+    rtd.state.forget_finished(&sessions);
     let scripts = process::Command::new("get-running-youtube-scripts").output().context(Io)?.stdout;
     let num_scripts = bytecount::count(&scripts, b'\n');
-    Ok(format!("{}/{} downloaders, {} scripts running", sessions.len(), rtd.conf.params.task_limit, num_scripts))
+    let settings = rtd.conf.channel_settings(channel);
+    let tasks_limit = settings.task_limit.unwrap_or(rtd.conf.params.task_limit);
+    let videos_limit = settings.video_task_limit.unwrap_or(rtd.conf.params.video_task_limit);
+    Ok(format!(
+        "{}/{} downloaders ({}/{} videos, {} paused), {} queued, {} scripts running",
+        sessions.len(), tasks_limit,
+        rtd.state.running_videos(&sessions), videos_limit,
+        rtd.state.paused.len(), rtd.state.queue.len(), num_scripts
+    ))
 }
 
-fn get_downloader_sessions() -> Result<Vec<DownloaderSession>> {
-    let output = process::Command::new("tmux")
-        .arg("list-sessions")
-        .arg("-F")
-        .arg("#{session_created} #S")
-        .output()
-        .context(Io)?;
-    let stdout_utf8 = str::from_utf8(&output.stdout).context(Utf8)?;
-    let sessions =
-        stdout_utf8.lines()
-            .filter_map(|line| {
-                let parts = line.splitn(2, ' ').collect::<Vec<&str>>();
-                let start_time   = parts.get(0).unwrap().parse::<u64>().unwrap();
-                let session_name = parts.get(1).unwrap();
-                if session_name.starts_with("YouTube-") {
-                    let identifier = session_name.replacen("YouTube-", "", 1);
-                    Some(DownloaderSession { identifier, start_time })
-                } else {
-                    None
-                }
-            }).collect();
-    Ok(sessions)
+// Alert about (or abort) tasks whose stash hasn't changed for too long
+pub fn check_stalled(clients: &[IrcClient], rtd: &mut Rtd) -> Result<()> {
+    let stall_timeout = rtd.conf.watchdog.stall_timeout;
+    if stall_timeout == 0 {
+        return Ok(());
+    }
+    let sessions = get_downloader_sessions()?;
+    rtd.state.forget_finished(&sessions);
+    let now = unix_time();
+    for session in sessions {
+        let folder = session.identifier;
+        if rtd.state.paused.contains(&folder) {
+            continue;
+        }
+        let listing = match get_file_listing(&folder) {
+            Ok(listing) => listing,
+            Err(_) => continue,
+        };
+        let files = listing.len();
+        let newest = listing.into_iter().next();
+        let progress = rtd.state.progress.entry(folder.clone()).or_insert_with(|| {
+            TaskProgress { files, newest: newest.clone(), changed_at: now, alerted: false }
+        });
+        if progress.files != files || progress.newest != newest {
+            *progress = TaskProgress { files, newest, changed_at: now, alerted: false };
+            continue;
+        }
+        let stalled_for = now.saturating_sub(progress.changed_at);
+        if progress.alerted || stalled_for < stall_timeout {
+            continue;
+        }
+        progress.alerted = true;
+        let stalled_for = humanize_duration(stalled_for);
+        let (requester, network, channel) = match rtd.state.tasks.get(&folder) {
+            Some(task) => (Some(task.requester.clone()), task.network, task.channel.clone()),
+            None => (None, 0, None),
+        };
+        let result = if rtd.conf.watchdog.abort_stalled {
+            let job = AbortJob {
+                context: format!("{} made no progress for {}; ", folder, stalled_for),
+                ..AbortJob::new(vec![folder.clone()], requester.clone(), network, channel.clone())
+            };
+            // the result is reported once the task exits
+            match start_abort(job, rtd) {
+                Ok(()) => continue,
+                Err(err) => Err(err),
+            }
+        } else {
+            Ok(format!("{} has made no progress for {}; see {}", folder, stalled_for, logs_url(&folder)))
+        };
+        report_task(clients, network, channel.as_deref(), requester.as_deref(), result, rtd);
+    }
+    Ok(())
 }
 
-fn get_help() -> Result<String> {
+// Tell whoever requested a task about it, or every network's command
+// channel if the requester isn't known
+fn report_task(clients: &[IrcClient], network: usize, channel: Option<&str>, requester: Option<&str>, result: Result<String>, rtd: &Rtd) {
+    if let Some(requester) = requester {
+        return reply_to_requester(clients, network, channel, requester, result, rtd);
+    }
+    let reply = match result {
+        Ok(reply) => reply,
+        Err(err)  => format!("error: {}", err),
+    };
+    let channel = &rtd.conf.params.command_channel;
+    for (network, client) in clients.iter().enumerate() {
+        let reply = channel_mentions_masked(reply.clone(), network, channel, rtd);
+        send_message(client, network, channel, "", &reply, reply_mode(None, None, rtd), rtd);
+    }
+}
+
+fn humanize_duration(seconds: u64) -> String {
+    match seconds {
+        s if s < 60        => format!("{}s", s),
+        s if s < 60 * 60   => format!("{}m", s / 60),
+        s if s < 24 * 3600 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+        s                  => format!("{}d{:02}h", s / (24 * 3600), s % (24 * 3600) / 3600),
+    }
+}
+
+// keeps each page of `!tasks` comfortably within an IRC line
+const TASKS_PAGE_LENGTH: usize = 350;
+
+fn paginate(entries: Vec<String>, max_length: usize) -> Vec<String> {
+    let mut pages: Vec<String> = vec![];
+    for entry in entries {
+        match pages.last_mut() {
+            Some(page) if page.len() + 2 + entry.len() <= max_length => {
+                page.push_str(", ");
+                page.push_str(&entry);
+            },
+            _ => pages.push(entry),
+        }
+    }
+    pages
+}
+
+fn get_tasks(page: Option<&str>, rtd: &mut Rtd) -> Result<String> {
+    let page = match page {
+        None => 1,
+        Some(page) => match page.parse::<usize>() {
+            Ok(page) if page > 0 => page,
+            _ => return UnexpectedArguments { what: format!("invalid page {}", page) }.fail(),
+        },
+    };
+    let mut sessions = get_downloader_sessions()?;
+    rtd.state.forget_finished(&sessions);
+    if sessions.is_empty() {
+        return Ok("No tasks running".to_string());
+    }
+    sessions.sort_by_key(|session| session.start_time);
+    let now = unix_time();
+    let entries = sessions
+        .iter()
+        .map(|session| {
+            let mut age = humanize_duration(now.saturating_sub(session.start_time));
+            if rtd.state.paused.contains(&session.identifier) {
+                age.push_str(", paused");
+            }
+            match rtd.state.tasks.get(&session.identifier) {
+                Some(task) => {
+                    let mut by = highlight_for_user(&task.requester, rtd);
+                    if let Some(approver) = &task.approver {
+                        by.push_str(&format!(", approved by {}", highlight_for_user(approver, rtd)));
+                    }
+                    format!("{} ({}, by {}, {})", session.identifier, age, by, task.profile)
+                },
+                None => format!("{} ({})", session.identifier, age),
+            }
+        })
+        .collect::<Vec<_>>();
+    let pages = paginate(entries, TASKS_PAGE_LENGTH);
+    ensure!(page <= pages.len(), UnexpectedArguments { what: format!("there are only {} pages", pages.len()) });
+    let more = if page < pages.len() {
+        format!("; see !tasks {}", page + 1)
+    } else {
+        String::new()
+    };
+    Ok(format!("{} tasks, page {}/{}: {}{}", sessions.len(), page, pages.len(), pages[page - 1], more))
+}
+
+fn get_help(topic: Option<&str>) -> Result<String> {
+    if let Some(topic) = topic {
+        return match options_help(topic) {
+            Some(help) => Ok(help),
+            None => UnexpectedArguments { what: format!("no help for {}", topic) }.fail(),
+        };
+    }
     Ok(
         "Usage: \
-        !help | \
+        !help [options | <option>] | \
         !status | \
+        !tasks [page] | \
         !s <URL or folder> | \
-        !a <URL> | \
-        !sa <URL> | \
-        !averybig <URL w/ very large videos> | \
-        !saverybig <URL w/ very large videos> | \
+        !a [options] <URL> | \
+        !sa [options] <URL> | \
+        !retry <task> | \
+        !pending | \
+        !approve <id> | \
+        !reject <id> [reason] | \
         !abort <task> | \
+        !abort --all | --user <nick> | --match <glob> | \
+        !pause <task> | \
+        !resume <task> | \
         !stopscripts | \
-        !contscripts".to_string()
+        !contscripts | \
+        !deny [list] | add <glob> [reply] | remove <glob> | \
+        !block [list] | add <id> | remove <id>".to_string()
     )
 }
 
@@ -378,9 +1011,31 @@ fn replace_matching_characters(input_str: &str, from_set: &str, to_set: &str) ->
         .collect::<String>()
 }
 
+// who a nick on a network is, for its settings, limits and tasks
+pub fn identity_for(network: usize, nick: &str, account: Option<&str>, rtd: &Rtd) -> Identity {
+    match account {
+        Some(account) if rtd.conf.params.key_by_account => Identity::Account(network, account.to_string()),
+        _ => Identity::Nick(network, nick.to_string()),
+    }
+}
+
+// a user's entry in a table keyed like user_highlights
+fn user_setting<'a, T>(user: &str, settings: &'a HashMap<String, T>, rtd: &Rtd) -> Option<&'a T> {
+    // the account of whoever uses the nick on any network; settings don't
+    // need to tell them apart
+    let account = rtd.state.users.iter().enumerate().find_map(|(network, users)| Some((network, users.account(user)?)));
+    let identity = match account {
+        Some((network, account)) => identity_for(network, user, Some(account), rtd),
+        None => identity_for(0, user, None, rtd),
+    };
+    match identity {
+        Identity::Nick(..) if rtd.conf.params.key_by_account => None,
+        identity => settings.get(identity.name()),
+    }
+}
+
 fn highlight_for_user(user: &str, rtd: &Rtd) -> String {
-    let user_highlights = &rtd.conf.user_highlights;
-    if let Some(mode) = user_highlights.get(user) {
+    if let Some(mode) = user_setting(user, &rtd.conf.user_highlights, rtd) {
         match mode {
             HighlightMode::Normal      => user.to_string(),
             HighlightMode::Fraktur     => replace_matching_characters(user, ALPHA_REGULAR, ALPHA_FRAKTUR),
@@ -395,11 +1050,190 @@ fn highlight_for_user(user: &str, rtd: &Rtd) -> String {
     }
 }
 
-fn send_reply(client: &IrcClient, channel: &str, user: &str, result: Result<String>, rtd: &Rtd) {
-    let user = highlight_for_user(user, rtd);
-    match result {
-        Ok(reply) => client.send_privmsg(channel, format!("{}: {}", user, reply)).unwrap(),
-        Err(err)  => client.send_privmsg(channel, format!("{}: error: {}", user, err)).unwrap(),
+// a nick spelled so it doesn't highlight its user: in their highlight
+// style if they have one, otherwise with a zero-width space after the
+// first character
+fn mask_nick(nick: &str, rtd: &Rtd) -> String {
+    match user_setting(nick, &rtd.conf.user_highlights, rtd) {
+        None | Some(HighlightMode::Normal) => {
+            let mut chars = nick.chars();
+            chars.next().map_or_else(String::new, |first| format!("{}\u{200B}{}", first, chars.as_str()))
+        },
+        Some(_) => highlight_for_user(nick, rtd),
+    }
+}
+
+// mask every mention of one of `nicks` in `text`, except in URLs, where
+// it would break the link
+fn mask_mentions<'a>(text: &str, nicks: impl IntoIterator<Item = &'a str>, rtd: &Rtd) -> String {
+    let nicks = nicks.into_iter().map(str::to_lowercase).collect::<HashSet<_>>();
+    let longest = nicks.iter().map(String::len).max().unwrap_or(0);
+    let words = text
+        .split(' ')
+        .map(|word| if word.contains("://") { word.to_string() } else { mask_word(word, &nicks, longest, rtd) })
+        .collect::<Vec<_>>();
+    words.join(" ")
+}
+
+// mask the nicks in a word that aren't part of a longer run of letters and
+// digits, preferring the longest nick where several start at one place
+fn mask_word(word: &str, nicks: &HashSet<String>, longest: usize, rtd: &Rtd) -> String {
+    // nicks are ASCII, so lowercasing keeps byte offsets
+    let lower = word.to_ascii_lowercase();
+    let starts_word = |idx: usize| !matches!(word[..idx].chars().next_back(), Some(c) if c.is_alphanumeric());
+    let ends_word = |idx: usize| !matches!(word[idx..].chars().next(), Some(c) if c.is_alphanumeric());
+    let mut masked = String::new();
+    let mut copied = 0;
+    let mut idx = 0;
+    while let Some(c) = word[idx..].chars().next() {
+        let end = if starts_word(idx) {
+            (idx + 1..=word.len().min(idx + longest))
+                .rev()
+                .find(|&end| word.is_char_boundary(end) && ends_word(end) && nicks.contains(&lower[idx..end]))
+        } else {
+            None
+        };
+        match end {
+            Some(end) => {
+                masked.push_str(&word[copied..idx]);
+                masked.push_str(&mask_nick(&word[idx..end], rtd));
+                copied = end;
+                idx = end;
+            },
+            None => idx += c.len_utf8(),
+        }
+    }
+    masked.push_str(&word[copied..]);
+    masked
+}
+
+// NOTICE or PRIVMSG for a reply to `user` about `command`
+fn reply_mode(user: Option<&str>, command: Option<&str>, rtd: &Rtd) -> ReplyMode {
+    let user_mode = user.and_then(|user| user_setting(user, &rtd.conf.user_reply_modes, rtd));
+    let command_mode = command.and_then(|command| rtd.conf.command_reply_modes.get(command));
+    match user_mode.or(command_mode) {
+        Some(mode) => *mode,
+        None if rtd.conf.features.send_notice => ReplyMode::Notice,
+        None => ReplyMode::Privmsg,
+    }
+}
+
+// including the CR LF
+const IRC_LINE_LIMIT: usize = 512;
+
+// how much text fits in one message to `target`, given the prefix the
+// server relays it with
+fn message_width(client: &IrcClient, network: usize, target: &str, mode: ReplyMode, rtd: &Rtd) -> usize {
+    let prefix = match rtd.state.users.get(network).and_then(Users::own_prefix) {
+        Some(prefix) => prefix.len(),
+        // until we've seen it, assume a 10 character ~username and the
+        // longest hostname
+        None => client.current_nickname().len() + 1 + 10 + 1 + 63,
+    };
+    let command = match mode {
+        ReplyMode::Privmsg => "PRIVMSG",
+        ReplyMode::Notice  => "NOTICE",
+    };
+    // ":<prefix> <command> <target> :<text>\r\n"
+    IRC_LINE_LIMIT.saturating_sub(prefix + command.len() + target.len() + 7)
+}
+
+// the start of `text` that fits in `width` bytes, broken at a space if
+// there is one, and the rest
+fn split_line(text: &str, width: usize) -> (&str, &str) {
+    if text.len() <= width {
+        return (text, "");
+    }
+    let mut end = width;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    if !text[end..].starts_with(' ') {
+        if let Some(space) = text[..end].rfind(' ').filter(|&space| space > 0) {
+            end = space;
+        }
+    }
+    // always make progress, even if a single character doesn't fit
+    if end == 0 {
+        end = text.chars().next().map_or(0, char::len_utf8);
+    }
+    (&text[..end], text[end..].trim_start_matches(' '))
+}
+
+// `text` as lines of at most `width` bytes; past `max_lines`, the last one
+// ends in how many more words there were. Words rather than items, since
+// replies aren't all lists and file names in them can contain spaces.
+fn split_reply(text: &str, width: usize, max_lines: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut rest = text;
+    loop {
+        let (line, more) = split_line(rest, width);
+        if more.is_empty() {
+            lines.push(line.to_string());
+            return lines;
+        }
+        if lines.len() + 1 >= max_lines {
+            // room for " …and 9999 more words"
+            let (line, more) = split_line(rest, width.saturating_sub(24));
+            lines.push(format!("{} …and {} more words", line, more.split_whitespace().count()));
+            return lines;
+        }
+        lines.push(line.to_string());
+        rest = more;
+    }
+}
+
+// send `text` after `lead`, split to fit in as few messages as needed
+fn send_message(client: &IrcClient, network: usize, target: &str, lead: &str, text: &str, mode: ReplyMode, rtd: &Rtd) {
+    let width = message_width(client, network, target, mode, rtd).saturating_sub(lead.len());
+    for line in split_reply(text, width, rtd.conf.params.max_reply_lines) {
+        let line = format!("{}{}", lead, line);
+        match mode {
+            ReplyMode::Privmsg => client.send_privmsg(target, line).unwrap(),
+            ReplyMode::Notice  => client.send_notice(target, line).unwrap(),
+        }
+    }
+}
+
+fn send_reply(client: &IrcClient, network: usize, channel: &str, user: &str, command: Option<&str>, result: Result<String>, rtd: &Rtd) {
+    let reply = match result {
+        Ok(reply) => reply,
+        Err(err)  => format!("error: {}", err),
+    };
+    let reply = channel_mentions_masked(reply, network, channel, rtd);
+    let mode = reply_mode(Some(user), command, rtd);
+    match rtd.conf.channel_settings(Some(channel)).reply_style {
+        ReplyStyle::Highlight => send_message(client, network, channel, &format!("{}: ", highlight_for_user(user, rtd)), &reply, mode, rtd),
+        ReplyStyle::Plain     => send_message(client, network, channel, "", &reply, mode, rtd),
+    }
+}
+
+// with mask_highlights, a reply to a channel doesn't highlight anyone in
+// it other than whoever it's addressed to
+fn channel_mentions_masked(reply: String, network: usize, channel: &str, rtd: &Rtd) -> String {
+    match rtd.state.users.get(network) {
+        Some(users) if rtd.conf.features.mask_highlights => mask_mentions(&reply, users.nicks(channel), rtd),
+        _ => reply,
+    }
+}
+
+fn send_private_reply(client: &IrcClient, network: usize, user: &str, command: Option<&str>, result: Result<String>, rtd: &Rtd) {
+    let reply = match result {
+        Ok(reply) => reply,
+        Err(err)  => format!("error: {}", err),
+    };
+    send_message(client, network, user, "", &reply, reply_mode(Some(user), command, rtd), rtd);
+}
+
+// reply to a requester where they made a request, long after the command
+fn reply_to_requester(clients: &[IrcClient], network: usize, channel: Option<&str>, user: &str, result: Result<String>, rtd: &Rtd) {
+    let client = match clients.get(network) {
+        Some(client) => client,
+        None => return,
+    };
+    match channel {
+        Some(channel) => send_reply(client, network, channel, user, None, result, rtd),
+        None => send_private_reply(client, network, user, None, result, rtd),
     }
 }
 
@@ -409,22 +1243,280 @@ fn extract_url(msg: &str) -> Result<&str> {
     Ok(url)
 }
 
-pub fn dispatch_message(message: &str, user: &str, rtd: &Rtd, check_authorization: impl Fn() -> Result<()>) -> Result<Vec<Result<String>>> {
+// `!deny [list]`, `!deny add <glob> [reply]` or `!deny remove <glob>`;
+// changes are saved to the configuration file
+fn deny_command(args: &str, rtd: &mut Rtd) -> Result<String> {
+    let args = args.trim();
+    let (action, args) = args.split_once(' ').unwrap_or((args, ""));
+    let (pattern, reply) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+    let deny = &mut rtd.conf.deny;
+    let position = deny.iter().position(|rule| rule.pattern.eq_ignore_ascii_case(pattern));
+    let reply = match (action, pattern.is_empty()) {
+        ("", _) | ("list", _) => {
+            if deny.is_empty() {
+                return Ok("No deny patterns".to_string());
+            }
+            let patterns = deny.iter().map(|rule| rule.pattern.as_str()).collect::<Vec<_>>();
+            return Ok(format!("Deny patterns: {}", patterns.join(" | ")));
+        },
+        ("add", false) => {
+            let rule = DenyRule { pattern: pattern.to_string(), reply: reply.trim().to_string() };
+            match position {
+                Some(position) => deny[position] = rule,
+                None => deny.push(rule),
+            }
+            format!("Denying {}", pattern)
+        },
+        ("remove", false) => {
+            match position {
+                Some(position) => deny.remove(position),
+                None => return NoSuchDenyPattern { pattern }.fail(),
+            };
+            format!("No longer denying {}", pattern)
+        },
+        _ => return UnexpectedArguments { what: "expected list, add <glob> [reply] or remove <glob>" }.fail(),
+    };
+    rtd.conf.write(&rtd.paths.conf)?;
+    Ok(reply)
+}
+
+// `!block [list]`, `!block add <id>` or `!block remove <id>`, for
+// channel IDs, folders, playlist IDs and video IDs; changes are saved to
+// the configuration file
+fn block_command(args: &str, rtd: &mut Rtd) -> Result<String> {
+    let ids = &mut rtd.conf.blocklist.ids;
+    let reply = match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [] | ["list"] => {
+            if ids.is_empty() {
+                return Ok("The blocklist is empty".to_string());
+            }
+            return Ok(format!("Blocklist: {}", summarize_list(ids, 20)));
+        },
+        ["add", id] => {
+            if !ids.iter().any(|entry| entry == id) {
+                ids.push(id.to_string());
+            }
+            format!("Blocked {}", id)
+        },
+        ["remove", id] => {
+            match ids.iter().position(|entry| entry == id) {
+                Some(position) => ids.remove(position),
+                None => return NotBlocked { id: *id }.fail(),
+            };
+            format!("Unblocked {}", id)
+        },
+        _ => return UnexpectedArguments { what: "expected list, add <id> or remove <id>" }.fail(),
+    };
+    rtd.conf.write(&rtd.paths.conf)?;
+    Ok(reply)
+}
+
+// who sent a command, and what they may do
+pub struct Sender<'a> {
+    pub nick: &'a str,
+    pub hostmask: &'a str,
+    pub account: Option<&'a str>,
+    pub identity: Identity,
+    pub role: Role,
+    // index of the connection the command was received on
+    pub network: usize,
+    // the command channel the command was sent to, None for a private message
+    pub channel: Option<&'a str>,
+}
+
+fn is_member(members: &RoleMembers, hostmask: &str, account: Option<&str>) -> bool {
+    members.hostmasks.iter().any(|pattern| glob_match(pattern, hostmask))
+        || matches!(account, Some(account) if members.accounts.iter().any(|a| a.eq_ignore_ascii_case(account)))
+}
+
+pub fn role_for(hostmask: &str, account: Option<&str>, rtd: &Rtd) -> Role {
+    let roles = &rtd.conf.roles;
+    if is_member(&roles.denied, hostmask, account) {
+        Role::Denied
+    } else if is_member(&roles.admin, hostmask, account) {
+        Role::Admin
+    } else if is_member(&roles.trusted, hostmask, account) {
+        Role::Trusted
+    } else {
+        rtd.conf.params.default_role
+    }
+}
+
+fn require_role(sender: &Sender, role: Role) -> Result<()> {
+    ensure!(sender.role >= role, RoleRequired { role });
+    Ok(())
+}
+
+// Privileged commands in private messages need a higher role, and the
+// sender to be logged in to services if configured
+fn check_private_command(sender: &Sender, rtd: &Rtd) -> Result<()> {
+    let params = &rtd.conf.params;
+    ensure!(sender.role >= params.private_role, PrivateRoleRequired { role: params.private_role });
+    ensure!(sender.account.is_some() || !params.private_require_account, AccountRequired);
+    Ok(())
+}
+
+// Check the prefix mode configured for the class of a command, which the
+// sender has to hold in the command channel unless they are an admin
+fn require_channel_mode(sender: &Sender, command: &CommandSpec, rtd: &Rtd) -> Result<()> {
+    let modes = &rtd.conf.channel_modes;
+    let required = if command.privileged { &modes.privileged } else { &modes.reporting };
+    let mode = match required.chars().next() {
+        Some(mode) if sender.role < Role::Admin => mode,
+        _ => return Ok(()),
+    };
+    let channel = sender.channel.unwrap_or(&rtd.conf.params.command_channel);
+    let held = matches!(rtd.state.users.get(sender.network), Some(users) if users.has_prefix(channel, sender.nick, mode));
+    ensure!(held, ChannelModeRequired { mode, channel });
+    Ok(())
+}
+
+// Take a token from the sender's bucket for the class of a command
+fn check_rate_limit(sender: &Sender, command: &CommandSpec, now: u64, rtd: &mut Rtd) -> Result<()> {
+    if sender.role >= Role::Admin {
+        return Ok(());
+    }
+    let limits = &rtd.conf.rate_limits;
+    let limit = if command.privileged { limits.privileged } else { limits.reporting };
+    if limit.burst == 0 {
+        return Ok(());
+    }
+    let buckets = &mut rtd.state.rate_limits;
+    // forget users who haven't sent commands for a while
+    buckets.retain(|&(_, _, privileged), bucket| {
+        privileged != command.privileged || !bucket.is_full(&limit, now)
+    });
+    let bucket = buckets
+        .entry((sender.network, sender.identity.name().to_lowercase(), command.privileged))
+        .or_insert_with(|| TokenBucket::new(&limit, now));
+    match bucket.take(&limit, now) {
+        Ok(()) => Ok(()),
+        Err(wait) => RateLimited { wait: humanize_duration(wait.max(1)) }.fail(),
+    }
+}
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    // minimum role needed to use the command
+    pub role: Role,
+    // whether the command acts on tasks or scripts, rather than only
+    // reporting on them; these go through `check_authorization`
+    pub privileged: bool,
+}
+
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec { name: "!help",        role: Role::User,    privileged: false },
+    CommandSpec { name: "!status",      role: Role::User,    privileged: false },
+    CommandSpec { name: "!tasks",       role: Role::User,    privileged: false },
+    CommandSpec { name: "!s",           role: Role::User,    privileged: false },
+    CommandSpec { name: "!a",           role: Role::User,    privileged: true },
+    CommandSpec { name: "!sa",          role: Role::User,    privileged: true },
+    CommandSpec { name: "!pending",     role: Role::User,    privileged: false },
+    CommandSpec { name: "!approve",     role: Role::Trusted, privileged: true },
+    CommandSpec { name: "!reject",      role: Role::Trusted, privileged: true },
+    CommandSpec { name: "!retry",       role: Role::User,    privileged: true },
+    CommandSpec { name: "!pause",       role: Role::User,    privileged: true },
+    CommandSpec { name: "!resume",      role: Role::User,    privileged: true },
+    CommandSpec { name: "!abort",       role: Role::User,    privileged: true },
+    CommandSpec { name: "!stopscripts", role: Role::Trusted, privileged: true },
+    CommandSpec { name: "!contscripts", role: Role::Trusted, privileged: true },
+    CommandSpec { name: "!deny",        role: Role::Admin,   privileged: true },
+    CommandSpec { name: "!block",       role: Role::Admin,   privileged: true },
+];
+
+pub fn dispatch_message(message: &str, sender: &Sender, rtd: &mut Rtd, check_authorization: impl Fn(&Rtd) -> Result<()>) -> Result<Vec<Result<String>>> {
+    let name = message.split(' ').next().unwrap_or("");
+    let command = match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => command,
+        None => return Ok(vec![]),
+    };
+    // denied users are ignored rather than told off, as are commands not
+    // meant for the channel
+    let allowed = &rtd.conf.channel_settings(sender.channel).commands;
+    if sender.role == Role::Denied || !(allowed.is_empty() || allowed.iter().any(|allowed| allowed == name)) {
+        return Ok(vec![]);
+    }
+    let checked = check_command(sender, command, rtd, check_authorization);
+    let refused = checked.is_err();
+    let replies = checked.and_then(|()| run_command(message, sender, rtd));
+    // privileged commands are logged with their results, and any command
+    // with why it was refused
+    if command.privileged || refused {
+        audit_command(message, sender, &replies, rtd);
+    }
+    replies
+}
+
+// whether the sender may run a command right now
+fn check_command(sender: &Sender, command: &CommandSpec, rtd: &mut Rtd, check_authorization: impl Fn(&Rtd) -> Result<()>) -> Result<()> {
+    require_role(sender, command.role)?;
+    require_channel_mode(sender, command, rtd)?;
+    check_rate_limit(sender, command, unix_time(), rtd)?;
+    if command.privileged {
+        check_authorization(rtd)?;
+    }
+    Ok(())
+}
+
+// Record a privileged command and its outcome in the audit log
+fn audit_command(message: &str, sender: &Sender, replies: &Result<Vec<Result<String>>>, rtd: &Rtd) {
+    if rtd.paths.audit_log.as_os_str().is_empty() {
+        return;
+    }
+    let (command, arguments) = message.split_once(' ').unwrap_or((message, ""));
+    let result = match replies {
+        Ok(replies) => replies
+            .iter()
+            .map(|reply| match reply {
+                Ok(reply) => reply.clone(),
+                Err(err) => format!("error: {}", err),
+            })
+            .collect(),
+        Err(err) => vec![format!("error: {}", err)],
+    };
+    let entry = AuditEntry {
+        timestamp: unix_time(),
+        nick: sender.nick,
+        hostmask: sender.hostmask,
+        account: sender.account,
+        command,
+        arguments: arguments.trim(),
+        result,
+    };
+    if let Err(err) = audit::append(&rtd.paths.audit_log, &entry) {
+        eprintln!("Error writing audit log: {}", err);
+    }
+}
+
+fn run_command(message: &str, sender: &Sender, rtd: &mut Rtd) -> Result<Vec<Result<String>>> {
     Ok(match message {
         "!help" => {
-            vec![get_help()]
+            vec![get_help(None)]
+        },
+        msg if msg.starts_with("!help ") => {
+            vec![get_help(msg.split_whitespace().nth(1))]
         },
         "!status" => {
-            vec![get_status(rtd)]
+            vec![get_status(sender.channel, rtd)]
+        },
+        "!tasks" => {
+            vec![get_tasks(None, rtd)]
+        },
+        msg if msg.starts_with("!tasks ") => {
+            vec![get_tasks(msg.split_whitespace().nth(1), rtd)]
         },
         "!stopscripts" => {
-            check_authorization()?;
             vec![stop_scripts()]
         },
         "!contscripts" => {
-            check_authorization()?;
             vec![cont_scripts()]
         },
+        msg if msg == "!deny" || msg.starts_with("!deny ") => {
+            vec![deny_command(&msg["!deny".len()..], rtd)]
+        },
+        msg if msg == "!block" || msg.starts_with("!block ") => {
+            vec![block_command(&msg["!block".len()..], rtd)]
+        },
         msg if msg.starts_with("!s ") => {
             let url_or_folder = extract_url(msg)?;
             if url_or_folder.starts_with("https://") || url_or_folder.starts_with("http://") {
@@ -434,89 +1526,131 @@ pub fn dispatch_message(message: &str, user: &str, rtd: &Rtd, check_authorizatio
                 vec![check_folder(&url_or_folder)]
             }
         },
-        msg if msg.starts_with("!a ") => {
-            check_authorization()?;
-            let url = extract_url(msg)?;
-            let descriptor = YoutubeDescriptor::from_url(&url)?.canonicalize()?;
-            vec![archive(&url, &descriptor, VideoSize::Normal, &user, &rtd)]
+        msg if msg.starts_with("!a ") || msg.starts_with("!sa ") => {
+            let (command, args) = msg.split_at(msg.find(' ').unwrap());
+            let (options, url) = parse_grab_command(args)?;
+            profile_for(&options, sender.channel, rtd)?;
+            let descriptor = YoutubeDescriptor::from_url(url)?.canonicalize()?;
+            options.check_applies_to(descriptor.kind)?;
+            let mut replies = vec![];
+            if command == "!sa" {
+                replies.push(check_stash(&descriptor));
+            }
+            replies.push(submit(new_request(url, &descriptor, &options, sender), sender, rtd));
+            replies
         },
-        msg if msg.starts_with("!sa ") => {
-            check_authorization()?;
-            let url = extract_url(msg)?;
-            let descriptor = YoutubeDescriptor::from_url(&url)?.canonicalize()?;
-            vec![
-                check_stash(&descriptor),
-                archive(&url, &descriptor, VideoSize::Normal, &user, &rtd)
-            ]
+        "!pending" => {
+            vec![get_pending(rtd)]
         },
-        msg if msg.starts_with("!averybig ") => {
-            check_authorization()?;
-            let url = extract_url(msg)?;
-            let descriptor = YoutubeDescriptor::from_url(&url)?.canonicalize()?;
-            vec![archive(&url, &descriptor, VideoSize::VeryBig, &user, &rtd)]
+        msg if msg.starts_with("!approve ") => {
+            let id = extract_url(msg)?;
+            vec![approve(id, sender, rtd)]
         },
-        msg if msg.starts_with("!saverybig ") => {
-            check_authorization()?;
-            let url = extract_url(msg)?;
-            let descriptor = YoutubeDescriptor::from_url(&url)?.canonicalize()?;
-            vec![
-                check_stash(&descriptor),
-                archive(&url, &descriptor, VideoSize::VeryBig, &user, &rtd)
-            ]
+        msg if msg.starts_with("!reject ") => {
+            vec![reject(&msg["!reject ".len()..], rtd)]
         },
-        msg if msg.starts_with("!abort ") => {
-            check_authorization()?;
+        msg if msg.starts_with("!retry ") => {
+            let task = extract_url(msg)?;
+            vec![retry(task, sender, rtd)]
+        },
+        msg if msg.starts_with("!pause ") => {
+            let task = extract_url(msg)?;
+            check_task_owner(task, sender, rtd)?;
+            vec![set_paused(task, true, rtd)]
+        },
+        msg if msg.starts_with("!resume ") => {
             let task = extract_url(msg)?;
-            vec![abort(&task)]
+            check_task_owner(task, sender, rtd)?;
+            vec![set_paused(task, false, rtd)]
+        },
+        msg if msg.starts_with("!abort ") => {
+            vec![abort_command(&msg["!abort ".len()..], sender, rtd)]
         },
         _other => vec![],
     })
 }
 
-const NO_WEBCHAT_MESSAGE: &str =
-    "webchat users are not authorized; use any other IRC client, or ask someone else to do it";
-
-pub fn handle_message(client: &IrcClient, message: &Message, rtd: &Rtd) -> Result<()> {
+// Handle a message received on the connection with index `network`
+pub fn handle_message(client: &IrcClient, network: usize, message: &Message, rtd: &mut Rtd) -> Result<()> {
     // print the message if debug flag is set
     if rtd.args.flag_debug {
         eprintln!("{:?}", message.command)
     }
 
+    rtd.state.users_on(network).track(client, message);
+
     // match on message type
-    let (_target, msg) = match message.command {
+    let (target, msg) = match message.command {
         Command::PRIVMSG(ref target, ref msg) => (target, msg),
         _ => return Ok(()),
     };
 
     let user = message.source_nickname().unwrap();
-    let channel = &rtd.conf.params.command_channel;
+    let channel = target.as_str();
+    // private messages get their replies privately
+    let private = !target.is_channel_name();
+    if !private && !rtd.conf.is_command_channel(channel) {
+        return Ok(());
+    }
+    let command = msg.split(' ').next();
+    let reply = |result: Result<String>, rtd: &Rtd| {
+        if private {
+            send_private_reply(client, network, user, command, result, rtd);
+        } else {
+            send_reply(client, network, channel, user, command, result, rtd);
+        }
+    };
+    let hostmask = message.prefix.as_ref().map_or("", String::as_str);
+    let account = rtd.state.users_on(network).account(user).map(String::from);
+    let account = account.as_deref();
+    let sender = Sender {
+        nick: user,
+        hostmask,
+        account,
+        identity: identity_for(network, user, account, rtd),
+        role: role_for(hostmask, account, rtd),
+        network,
+        channel: if private { None } else { Some(channel) },
+    };
 
-    static WEBCHAT_RE: &Lazy<Regex> = lazy_regex!(r"\A.+!webchat@.+\z");
-    let check_authorization = || {
-        if let Some(prefix) = &message.prefix {
-            if WEBCHAT_RE.is_match(prefix) {
-                send_reply(client, channel, user, Ok(NO_WEBCHAT_MESSAGE.into()), rtd);
+    let check_authorization = |rtd: &Rtd| {
+        if let Some(rule) = rtd.conf.deny.iter().find(|rule| glob_match(&rule.pattern, hostmask)) {
+            if rule.reply.is_empty() {
                 return Err(Error::NotAuthorized);
             }
+            return Denied { reply: &rule.reply }.fail();
+        }
+        if private {
+            check_private_command(&sender, rtd)?;
         }
         Ok(())
     };
 
-    if message.response_target() == Some(channel) {
-        // Special handling
-        if user == "Ryz" && msg.starts_with("!archive ") {
-            client.send(format!("KICK {} {} :不明なコマンドです。", channel, user).as_str()).unwrap();
+    if !private {
+        if let Some(rule) = moderation::matching_rule(&sender, msg, rtd) {
+            match rule.action {
+                ModerationAction::Kick => client.send_kick(channel, user, &rule.reason).unwrap(),
+                ModerationAction::Quiet if !matches!(rtd.state.users.get(network), Some(users) if users.has_list_mode('q')) => {
+                    eprintln!("Not quieting {} in {}: the server has no +q quiet list", hostmask, channel);
+                },
+                ModerationAction::Quiet => {
+                    let quiet = Mode::Plus(ChannelMode::Unknown('q'), Some(moderation::quiet_mask(hostmask)));
+                    client.send_mode(channel, &[quiet]).unwrap()
+                },
+                ModerationAction::Reply => send_reply(client, network, channel, user, None, Ok(rule.reason.clone()), rtd),
+                ModerationAction::Ignore => (),
+            }
+            return Ok(());
         }
+    }
 
-        let replies = dispatch_message(&msg, &user, &rtd, &check_authorization);
-        match replies {
-            Err(err) => {
-                client.send_privmsg(channel, format!("{}: error: {}", user, err)).unwrap()
-            },
-            Ok(replies) => {
-                for reply in replies.into_iter() {
-                    send_reply(client, channel, user, reply, rtd);
-                }
+    let replies = dispatch_message(&msg, &sender, rtd, &check_authorization);
+    match replies {
+        Err(Error::Denied { reply: message }) => reply(Ok(message), rtd),
+        Err(err) => reply(Err(err), rtd),
+        Ok(replies) => {
+            for result in replies.into_iter() {
+                reply(result, rtd);
             }
         }
     }
@@ -526,6 +1660,7 @@ pub fn handle_message(client: &IrcClient, message: &Message, rtd: &Rtd) -> Resul
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{ChannelSettings, Conf};
 
     #[test]
     fn test_descriptor() {
@@ -571,4 +1706,360 @@ mod tests {
         assert_eq!(replace_matching_characters("User", ALPHA_REGULAR, ALPHA_FRAKTUR), "𝔘𝔰𝔢𝔯");
         assert_eq!(replace_matching_characters("_User0", ALPHA_REGULAR, ALPHA_FRAKTUR), "_𝔘𝔰𝔢𝔯0");
     }
+
+    #[test]
+    fn test_task_limit_reason() {
+        let mut rtd = Rtd::default();
+        rtd.conf.params.task_limit = 2;
+        rtd.conf.params.video_task_limit = 1;
+        let sessions = vec![
+            DownloaderSession { identifier: "a".to_string(), start_time: 0 },
+            DownloaderSession { identifier: "b".to_string(), start_time: 0 },
+        ];
+        rtd.state.tasks.insert("a".to_string(), TaskRecord { kind: FetchType::Channel, requester: "user".to_string(), approver: None, owner: Identity::Nick(0, "user".to_string()), approver_identity: None, profile: "default".to_string(), network: 0, channel: None });
+        let user = Identity::Nick(0, "user".to_string());
+        assert!(task_limit_reason(FetchType::Video, &user, None, &sessions, &rtd).is_none());
+        assert!(task_limit_reason(FetchType::Channel, &user, None, &sessions, &rtd).is_some());
+
+        rtd.state.tasks.insert("b".to_string(), TaskRecord { kind: FetchType::Video, requester: "user".to_string(), approver: None, owner: Identity::Nick(0, "user".to_string()), approver_identity: None, profile: "default".to_string(), network: 0, channel: None });
+        assert!(task_limit_reason(FetchType::Video, &user, None, &sessions, &rtd).is_some());
+
+        rtd.conf.user_limits.insert("trusted".to_string(), 3);
+        let trusted = Identity::Nick(0, "trusted".to_string());
+        assert!(task_limit_reason(FetchType::Playlist, &trusted, None, &sessions, &rtd).is_none());
+
+        // with key_by_account, only the logged in account gets its limit
+        rtd.conf.params.key_by_account = true;
+        assert!(task_limit_reason(FetchType::Playlist, &trusted, None, &sessions, &rtd).is_some());
+        assert!(task_limit_reason(FetchType::Playlist, &Identity::Account(0, "Trusted".to_string()), None, &sessions, &rtd).is_none());
+
+        // channels can have their own limits
+        rtd.conf.channels.insert("#ops".to_string(), ChannelSettings { task_limit: Some(5), ..ChannelSettings::default() });
+        assert!(task_limit_reason(FetchType::Playlist, &trusted, Some("#OPS"), &sessions, &rtd).is_none());
+        assert!(task_limit_reason(FetchType::Playlist, &trusted, Some("#other"), &sessions, &rtd).is_some());
+    }
+
+    #[test]
+    fn test_humanize_duration() {
+        assert_eq!(humanize_duration(5), "5s");
+        assert_eq!(humanize_duration(125), "2m");
+        assert_eq!(humanize_duration(3 * 3600 + 5 * 60), "3h05m");
+        assert_eq!(humanize_duration(2 * 24 * 3600 + 4 * 3600 + 59), "2d04h");
+    }
+
+    #[test]
+    fn test_paginate() {
+        let entries = vec!["aaaa".to_string(), "bbbb".to_string(), "cccc".to_string()];
+        assert_eq!(paginate(entries.clone(), 10), vec!["aaaa, bbbb", "cccc"]);
+        assert_eq!(paginate(entries.clone(), 100), vec!["aaaa, bbbb, cccc"]);
+        assert_eq!(paginate(entries, 1), vec!["aaaa", "bbbb", "cccc"]);
+    }
+
+    fn sender(nick: &str, role: Role) -> Sender<'_> {
+        Sender { nick, hostmask: "", account: None, identity: Identity::Nick(0, nick.to_string()), role, network: 0, channel: None }
+    }
+
+    #[test]
+    fn test_check_task_owner() {
+        let mut rtd = Rtd::default();
+        rtd.state.tasks.insert("a".to_string(), TaskRecord { kind: FetchType::Channel, requester: "owner".to_string(), approver: None, owner: Identity::Nick(0, "owner".to_string()), approver_identity: None, profile: "default".to_string(), network: 0, channel: None });
+        assert!(check_task_owner("a", &sender("owner", Role::User), &rtd).is_ok());
+        assert!(check_task_owner("a", &sender("OWNER", Role::User), &rtd).is_ok());
+        assert!(check_task_owner("a", &sender("admin", Role::Admin), &rtd).is_ok());
+        assert_eq!(
+            check_task_owner("a", &sender("someone", Role::Trusted), &rtd).unwrap_err().to_string(),
+            "a was started by owner; only they or an admin can do that"
+        );
+        assert!(check_task_owner("b", &sender("someone", Role::User), &rtd).is_ok());
+        // nor is the same nick on another network
+        let elsewhere = Sender { identity: Identity::Nick(1, "owner".to_string()), network: 1, ..sender("owner", Role::User) };
+        assert!(check_task_owner("a", &elsewhere, &rtd).is_err());
+
+        // someone who took the nick of an account owner isn't the owner
+        rtd.state.tasks.get_mut("a").unwrap().owner = Identity::Account(0, "owner".to_string());
+        assert!(check_task_owner("a", &sender("owner", Role::User), &rtd).is_err());
+        let account_owner = Sender { identity: Identity::Account(0, "Owner".to_string()), ..sender("newnick", Role::User) };
+        assert!(check_task_owner("a", &account_owner, &rtd).is_ok());
+
+        // whoever approved the task can control it too
+        rtd.state.tasks.get_mut("a").unwrap().approver_identity = Some(Identity::Nick(0, "trusted".to_string()));
+        assert!(check_task_owner("a", &sender("trusted", Role::Trusted), &rtd).is_ok());
+        assert!(check_task_owner("a", &account_owner, &rtd).is_ok());
+    }
+
+    #[test]
+    fn test_role_for() {
+        let mut rtd = Rtd::default();
+        rtd.conf.roles.admin.accounts.push("Boss".to_string());
+        rtd.conf.roles.trusted.hostmasks.push("*!*@trusted.example".to_string());
+        rtd.conf.roles.denied.hostmasks.push("*!*@*.spam".to_string());
+        rtd.conf.roles.denied.accounts.push("banned".to_string());
+        assert_eq!(role_for("nick!user@host", None, &rtd), Role::User);
+        assert_eq!(role_for("nick!user@host", Some("boss"), &rtd), Role::Admin);
+        assert_eq!(role_for("nick!user@trusted.example", None, &rtd), Role::Trusted);
+        assert_eq!(role_for("nick!user@trusted.example", Some("banned"), &rtd), Role::Denied);
+        assert_eq!(role_for("nick!user@host.spam", Some("boss"), &rtd), Role::Denied);
+        rtd.conf.params.default_role = Role::Denied;
+        assert_eq!(role_for("nick!user@host", None, &rtd), Role::Denied);
+    }
+
+    #[test]
+    fn test_dispatch_requires_role() {
+        let mut rtd = Rtd::default();
+        let authorized = |_: &Rtd| Ok(());
+        assert_eq!(
+            dispatch_message("!stopscripts", &sender("nick", Role::User), &mut rtd, authorized).unwrap_err().to_string(),
+            "Only users with the Trusted role can do that"
+        );
+        assert!(dispatch_message("!status", &sender("nick", Role::Denied), &mut rtd, authorized).unwrap().is_empty());
+        assert!(dispatch_message("!unknown", &sender("nick", Role::Admin), &mut rtd, authorized).unwrap().is_empty());
+
+        // commands not allowed in a channel are ignored there
+        rtd.conf.channels.insert("#public".to_string(), ChannelSettings { commands: vec!["!help".to_string()], ..ChannelSettings::default() });
+        let public = Sender { channel: Some("#public"), ..sender("nick", Role::Admin) };
+        assert_eq!(dispatch_message("!help", &public, &mut rtd, authorized).unwrap().len(), 1);
+        assert!(dispatch_message("!stopscripts", &public, &mut rtd, authorized).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_audit_refused_commands() {
+        let mut rtd = Rtd::default();
+        rtd.paths.audit_log = std::env::temp_dir().join(format!("youtube-irc-bot-refused-{}.jsonl", process::id()));
+        let authorized = |_: &Rtd| Ok(());
+        assert!(dispatch_message("!stopscripts", &sender("nick", Role::User), &mut rtd, authorized).is_err());
+        assert!(dispatch_message("!help", &sender("nick", Role::User), &mut rtd, authorized).is_ok());
+        rtd.conf.channel_modes.reporting = "+".to_string();
+        assert!(dispatch_message("!status", &sender("nick", Role::User), &mut rtd, authorized).is_err());
+        let log = std::fs::read_to_string(&rtd.paths.audit_log).unwrap();
+        std::fs::remove_file(&rtd.paths.audit_log).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#""command":"!stopscripts","arguments":"","result":["error: Only users with the Trusted role can do that"]"#));
+        assert!(lines[1].contains(r#""command":"!status","arguments":"","result":["error: You need + or higher in #youtubearchive to do that"]"#));
+    }
+
+    #[test]
+    fn test_check_private_command() {
+        let mut rtd = Rtd::default();
+        assert_eq!(
+            check_private_command(&sender("nick", Role::User), &rtd).unwrap_err().to_string(),
+            "Only users with the Trusted role can do that in private messages"
+        );
+        assert_eq!(
+            check_private_command(&sender("nick", Role::Trusted), &rtd).unwrap_err().to_string(),
+            "You need to be logged in to services to do that in private messages"
+        );
+        let logged_in = Sender { account: Some("acct"), ..sender("nick", Role::Trusted) };
+        assert!(check_private_command(&logged_in, &rtd).is_ok());
+        rtd.conf.params.private_require_account = false;
+        assert!(check_private_command(&sender("nick", Role::Trusted), &rtd).is_ok());
+    }
+
+    #[test]
+    fn test_require_channel_mode() {
+        let mut rtd = Rtd::default();
+        let privileged = COMMANDS.iter().find(|command| command.name == "!a").unwrap();
+        let reporting = COMMANDS.iter().find(|command| command.name == "!status").unwrap();
+        assert!(require_channel_mode(&sender("nick", Role::User), privileged, &rtd).is_ok());
+
+        rtd.conf.channel_modes.privileged = "+".to_string();
+        assert_eq!(
+            require_channel_mode(&sender("nick", Role::User), privileged, &rtd).unwrap_err().to_string(),
+            "You need + or higher in #youtubearchive to do that"
+        );
+        assert!(require_channel_mode(&sender("nick", Role::User), reporting, &rtd).is_ok());
+        assert!(require_channel_mode(&sender("admin", Role::Admin), privileged, &rtd).is_ok());
+    }
+
+    #[test]
+    fn test_check_rate_limit() {
+        let mut rtd = Rtd::default();
+        let status = COMMANDS.iter().find(|command| command.name == "!status").unwrap();
+        let archive = COMMANDS.iter().find(|command| command.name == "!a").unwrap();
+        for _ in 0..5 {
+            assert!(check_rate_limit(&sender("nick", Role::User), status, 100, &mut rtd).is_ok());
+        }
+        assert_eq!(
+            check_rate_limit(&sender("NICK", Role::User), status, 100, &mut rtd).unwrap_err().to_string(),
+            "Please slow down; try again in 10s"
+        );
+        assert!(check_rate_limit(&sender("nick", Role::User), archive, 100, &mut rtd).is_ok());
+        assert!(check_rate_limit(&sender("other", Role::User), status, 100, &mut rtd).is_ok());
+        assert!(check_rate_limit(&Sender { network: 1, ..sender("nick", Role::User) }, status, 100, &mut rtd).is_ok());
+        assert!(check_rate_limit(&sender("nick", Role::User), status, 110, &mut rtd).is_ok());
+        for _ in 0..10 {
+            assert!(check_rate_limit(&sender("admin", Role::Admin), status, 100, &mut rtd).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let mut rtd = Rtd::default();
+        rtd.paths.conf = std::env::temp_dir().join(format!("youtube-irc-bot-blocklist-{}.toml", process::id()));
+        let video = CanonicalizedYoutubeDescriptor {
+            kind: FetchType::Video,
+            id: "dQw4w9WgXcQ".to_string(),
+            folder: "RickAstleyVEVO".to_string(),
+            channel: Some("UCuAXFkgsw1L7xaCfnd5JJOw".to_string()),
+        };
+        assert_eq!(block_command("", &mut rtd).unwrap(), "The blocklist is empty");
+        assert_eq!(block_command(" add UCuAXFkgsw1L7xaCfnd5JJOw", &mut rtd).unwrap(), "Blocked UCuAXFkgsw1L7xaCfnd5JJOw");
+        assert!(video.blocked_by(&rtd.conf.blocklist.ids).is_some());
+        assert_eq!(
+            archive(new_request("https://youtu.be/dQw4w9WgXcQ", &video, &GrabOptions::default(), &sender("nick", Role::User)), &mut rtd).unwrap_err().to_string(),
+            "Can't archive https://youtu.be/dQw4w9WgXcQ: that can't be archived here"
+        );
+
+        block_command(" add RickAstleyVEVO", &mut rtd).unwrap();
+        block_command(" remove UCuAXFkgsw1L7xaCfnd5JJOw", &mut rtd).unwrap();
+        assert_eq!(video.blocked_by(&rtd.conf.blocklist.ids).unwrap(), "RickAstleyVEVO");
+        assert_eq!(block_command(" remove nothing", &mut rtd).unwrap_err().to_string(), "nothing is not on the blocklist");
+        assert_eq!(block_command(" list", &mut rtd).unwrap(), "Blocklist: RickAstleyVEVO");
+        assert_eq!(Conf::load(&rtd.paths.conf).unwrap().blocklist.ids, vec!["RickAstleyVEVO"]);
+        std::fs::remove_file(&rtd.paths.conf).unwrap();
+    }
+
+    #[test]
+    fn test_approval() {
+        let mut rtd = Rtd::default();
+        rtd.conf.params.require_approval = true;
+        let playlist = CanonicalizedYoutubeDescriptor {
+            kind: FetchType::Playlist,
+            id: "PLAF2D5C1F1AFFA8F7".to_string(),
+            folder: "PLAF2D5C1F1AFFA8F7".to_string(),
+            channel: None,
+        };
+        let url = "https://www.youtube.com/playlist?list=PLAF2D5C1F1AFFA8F7";
+        let user = sender("user", Role::User);
+        let trusted = sender("trusted", Role::Trusted);
+        assert_eq!(
+            submit(new_request(url, &playlist, &GrabOptions::default(), &user), &user, &mut rtd).unwrap(),
+            format!("{} -> PLAF2D5C1F1AFFA8F7 is waiting for approval by a trusted user as #1", url)
+        );
+        assert!(submit(new_request(url, &playlist, &GrabOptions::default(), &user), &user, &mut rtd).unwrap().contains("already waiting for approval as #1"));
+        assert_eq!(get_pending(&rtd).unwrap(), format!("Waiting for approval: #1 {} by user", url));
+
+        // a request for a folder that is already queued stays pending
+        rtd.state.queue.push_back(new_request(url, &playlist, &GrabOptions::default(), &trusted));
+        assert_eq!(
+            approve("#1", &trusted, &mut rtd).unwrap(),
+            "#1 is still waiting for approval: Can't archive https://www.youtube.com/playlist?list=PLAF2D5C1F1AFFA8F7 \
+             because another task for the same folder PLAF2D5C1F1AFFA8F7 is already queued"
+        );
+        assert_eq!(rtd.state.pending.len(), 1);
+        rtd.state.queue.clear();
+
+        // a request that can't be archived stays pending
+        rtd.conf.blocklist.ids.push("PLAF2D5C1F1AFFA8F7".to_string());
+        assert!(approve("#1", &trusted, &mut rtd).is_err());
+        assert_eq!(rtd.state.pending.len(), 1);
+        assert_eq!(
+            reject("1 not archivable", &mut rtd).unwrap(),
+            format!("Rejected #1 from user for {}: not archivable", url)
+        );
+        assert_eq!(approve("1", &trusted, &mut rtd).unwrap_err().to_string(), "No request #1 is waiting for approval");
+        assert_eq!(get_pending(&rtd).unwrap(), "No requests waiting for approval");
+    }
+
+    #[test]
+    fn test_deny_command() {
+        let mut rtd = Rtd::default();
+        rtd.paths.conf = std::env::temp_dir().join(format!("youtube-irc-bot-test-{}.toml", process::id()));
+        assert_eq!(deny_command("", &mut rtd).unwrap(), "Deny patterns: *!webchat@*");
+        assert!(glob_match(&rtd.conf.deny[0].pattern, "nick!webchat@127.0.0.1"));
+
+        assert_eq!(deny_command(" add *!*@*.example spammers go away", &mut rtd).unwrap(), "Denying *!*@*.example");
+        assert_eq!(deny_command(" remove *!webchat@*", &mut rtd).unwrap(), "No longer denying *!webchat@*");
+        assert_eq!(deny_command(" remove *!webchat@*", &mut rtd).unwrap_err().to_string(), "No deny pattern *!webchat@*");
+        assert!(deny_command(" add", &mut rtd).is_err());
+        assert!(Conf::load(&rtd.paths.conf).is_ok());
+        assert_eq!(deny_command(" remove *!*@*.example", &mut rtd).unwrap(), "No longer denying *!*@*.example");
+        assert!(Conf::load(&rtd.paths.conf).unwrap().deny.is_empty());
+        assert_eq!(deny_command(" add *!*@*.example spammers go away", &mut rtd).unwrap(), "Denying *!*@*.example");
+
+        let saved = Conf::load(&rtd.paths.conf).unwrap();
+        std::fs::remove_file(&rtd.paths.conf).unwrap();
+        assert_eq!(saved.deny.len(), 1);
+        assert_eq!(saved.deny[0].pattern, "*!*@*.example");
+        assert_eq!(saved.deny[0].reply, "spammers go away");
+    }
+
+    #[test]
+    fn test_abort_result() {
+        let rtd = Rtd::default();
+        let mut job = AbortJob::new(vec![], Some("nick".to_string()), 0, None);
+        job.outcomes = vec![("task".to_string(), AbortOutcome::Terminated)];
+        assert_eq!(abort_result(&job, &rtd).unwrap(), "Aborted task with SIGTERM after it ignored C-c for 10s");
+        job.outcomes = vec![("task".to_string(), AbortOutcome::StillRunning)];
+        assert_eq!(abort_result(&job, &rtd).unwrap_err().to_string(), "task is still running after killing its session");
+        job.bulk = true;
+        job.outcomes.push(("other".to_string(), AbortOutcome::Interrupted));
+        assert_eq!(
+            abort_result(&job, &rtd).unwrap(),
+            "Aborted 1 of 2 tasks (1 with C-c, 0 with SIGTERM, 0 killed); still running: task"
+        );
+    }
+
+    #[test]
+    fn test_reply_mode() {
+        let mut rtd = Rtd::default();
+        assert_eq!(reply_mode(Some("nick"), Some("!status"), &rtd), ReplyMode::Privmsg);
+        rtd.conf.features.send_notice = true;
+        assert_eq!(reply_mode(Some("nick"), Some("!status"), &rtd), ReplyMode::Notice);
+        assert_eq!(reply_mode(None, None, &rtd), ReplyMode::Notice);
+        rtd.conf.command_reply_modes.insert("!status".to_string(), ReplyMode::Privmsg);
+        assert_eq!(reply_mode(Some("nick"), Some("!status"), &rtd), ReplyMode::Privmsg);
+        assert_eq!(reply_mode(Some("nick"), Some("!tasks"), &rtd), ReplyMode::Notice);
+        rtd.conf.user_reply_modes.insert("nick".to_string(), ReplyMode::Notice);
+        assert_eq!(reply_mode(Some("nick"), Some("!status"), &rtd), ReplyMode::Notice);
+        assert_eq!(reply_mode(Some("other"), Some("!status"), &rtd), ReplyMode::Privmsg);
+    }
+
+    #[test]
+    fn test_mask_mentions() {
+        let mut rtd = Rtd::default();
+        let nicks = vec!["bob", "al", "[x]"];
+        assert_eq!(
+            mask_mentions("Bob: al's folder /bobby/al-2020 and [x], not balance", nicks.clone(), &rtd),
+            "B\u{200B}ob: a\u{200B}l's folder /bobby/a\u{200B}l-2020 and [\u{200B}x], not balance"
+        );
+        // links keep working
+        assert_eq!(
+            mask_mentions("logs at https://ya.borg.xyz/logs/dl/al/ for al", vec!["logs", "https", "ya", "al"], &rtd),
+            "l\u{200B}ogs at https://ya.borg.xyz/logs/dl/al/ for a\u{200B}l"
+        );
+        rtd.conf.user_highlights.insert("bob".to_string(), HighlightMode::Bold);
+        assert_eq!(mask_mentions("bob", nicks, &rtd), "𝐛𝐨𝐛");
+    }
+
+    #[test]
+    fn test_split_reply() {
+        assert_eq!(split_reply("short", 10, 4), vec!["short"]);
+        assert_eq!(split_reply("aaa bbb ccc ddd", 8, 4), vec!["aaa bbb", "ccc ddd"]);
+        assert_eq!(split_reply("aaaaaaaaaa", 4, 4), vec!["aaaa", "aaaa", "aa"]);
+        // not inside a multibyte character
+        assert_eq!(split_reply("ああああ", 7, 4), vec!["ああ", "ああ"]);
+        let words = (0..100).map(|n| format!("w{:02}", n)).collect::<Vec<_>>().join(" ");
+        let lines = split_reply(&words, 40, 3);
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() <= 40));
+        assert!(lines[2].ends_with(" …and 76 more words"), "{}", lines[2]);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("UC*", "UCsT0YIqwnpJCM-mx7-gSA4Q"));
+        assert!(glob_match("jblow88?", "jblow888"));
+        assert!(glob_match("*!*@*.example.com", "Nick!user@host.EXAMPLE.com"));
+        assert!(!glob_match("jblow88?", "jblow8888"));
+        assert!(!glob_match("a.c", "abc"));
+    }
+
+    #[test]
+    fn test_summarize_list() {
+        let items = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(summarize_list(&items, 3), "a, b, c");
+        assert_eq!(summarize_list(&items, 1), "a and 2 more");
+    }
 }
@@ -0,0 +1,80 @@
+/*
+ * Moderation rules for command channels
+ *
+ */
+use crate::config::{ModerationRule, Rtd};
+use crate::message::{glob_match, Sender};
+
+// is_none_or would need Rust 1.82
+#[allow(clippy::unnecessary_map_or)]
+fn rule_matches(rule: &ModerationRule, sender: &Sender, message: &str) -> bool {
+    let nick = rule.nick.as_ref().map_or(true, |pattern| glob_match(pattern, sender.nick));
+    let hostmask = rule.hostmask.as_ref().map_or(true, |pattern| glob_match(pattern, sender.hostmask));
+    let account = rule.account.as_ref().map_or(true, |account| {
+        matches!(sender.account, Some(sender) if sender.eq_ignore_ascii_case(account))
+    });
+    let message = rule.message.as_ref().map_or(true, |regex| regex.is_match(message));
+    nick && hostmask && account && message
+}
+
+// the first rule matching a message, if any
+pub fn matching_rule<'a>(sender: &Sender, message: &str, rtd: &'a Rtd) -> Option<&'a ModerationRule> {
+    rtd.conf.moderation.iter().find(|rule| rule_matches(rule, sender, message))
+}
+
+// mask for quieting everyone on a user's host
+pub fn quiet_mask(hostmask: &str) -> String {
+    match hostmask.rfind('@') {
+        Some(idx) => format!("*!*{}", &hostmask[idx..]),
+        None => format!("{}!*@*", hostmask),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ModerationAction, Role};
+    use crate::state::Identity;
+    use regex::Regex;
+
+    fn sender<'a>(nick: &'a str, hostmask: &'a str, account: Option<&'a str>) -> Sender<'a> {
+        Sender { nick, hostmask, account, identity: Identity::Nick(0, nick.to_string()), role: Role::User, network: 0, channel: None }
+    }
+
+    #[test]
+    fn test_matching_rule() {
+        let mut rtd = Rtd::default();
+        let ryz = sender("Ryz", "Ryz!user@host", None);
+        assert!(matching_rule(&ryz, "!archive https://youtu.be/x", &rtd).is_none());
+
+        rtd.conf.moderation = vec![ModerationRule {
+            nick: Some("Ryz".to_string()),
+            hostmask: None,
+            account: None,
+            message: Some(Regex::new("^!archive ").unwrap()),
+            action: ModerationAction::Kick,
+            reason: String::new(),
+        }];
+        assert_eq!(matching_rule(&ryz, "!archive https://youtu.be/x", &rtd).unwrap().action, ModerationAction::Kick);
+        assert!(matching_rule(&ryz, "!a https://youtu.be/x", &rtd).is_none());
+        assert!(matching_rule(&sender("other", "other!user@host", None), "!archive x", &rtd).is_none());
+
+        rtd.conf.moderation = vec![ModerationRule {
+            nick: None,
+            hostmask: Some("*!*@*.example".to_string()),
+            account: Some("spammer".to_string()),
+            message: None,
+            action: ModerationAction::Quiet,
+            reason: String::new(),
+        }];
+        assert!(matching_rule(&sender("a", "a!u@host.example", Some("Spammer")), "hi", &rtd).is_some());
+        assert!(matching_rule(&sender("a", "a!u@host.example", None), "hi", &rtd).is_none());
+        assert!(matching_rule(&sender("a", "a!u@elsewhere", Some("spammer")), "hi", &rtd).is_none());
+    }
+
+    #[test]
+    fn test_quiet_mask() {
+        assert_eq!(quiet_mask("nick!user@host.example"), "*!*@host.example");
+        assert_eq!(quiet_mask("nick"), "nick!*@*");
+    }
+}
@@ -0,0 +1,255 @@
+/*
+ * Options for archive commands
+ *
+ */
+use once_cell::sync::Lazy;
+use regex::Regex;
+use snafu::ensure;
+use crate::message::{
+    Error, FetchType, Result,
+    InvalidOptionValue, MissingOptionValue, OptionNotApplicable, UnexpectedArguments, UrlTooLong,
+};
+
+pub struct OptionSpec {
+    pub name: &'static str,
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+pub static GRAB_OPTIONS: &[OptionSpec] = &[
+    OptionSpec { name: "--profile", value: Some("NAME"), help: "grab using the named profile from the configuration" },
+    OptionSpec { name: "--big",     value: None,         help: "shorthand for --profile big, for very large videos" },
+    OptionSpec { name: "--limit",   value: Some("N"),    help: "grab at most N videos from a channel or playlist" },
+    OptionSpec { name: "--since",   value: Some("DATE"), help: "only grab videos uploaded on or after DATE (YYYY-MM-DD)" },
+    OptionSpec { name: "--until",   value: Some("DATE"), help: "only grab videos uploaded on or before DATE (YYYY-MM-DD)" },
+    OptionSpec { name: "--tab",     value: Some("TAB"),  help: "channel tab to grab: videos, streams or shorts" },
+];
+
+const CHANNEL_TABS: [&str; 3] = ["videos", "streams", "shorts"];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GrabOptions {
+    // the configured default profile is used if unset
+    pub profile: Option<String>,
+    pub limit: Option<u32>,
+    // dates are normalized to YYYYMMDD
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub tab: Option<String>,
+}
+
+impl GrabOptions {
+    // arguments appended to the channel grab command after the folder and limit
+    pub fn extra_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(since) = &self.since {
+            args.extend(vec!["--since".to_string(), since.clone()]);
+        }
+        if let Some(until) = &self.until {
+            args.extend(vec!["--until".to_string(), until.clone()]);
+        }
+        if let Some(tab) = &self.tab {
+            args.extend(vec!["--tab".to_string(), tab.clone()]);
+        }
+        args
+    }
+
+    // only the profile can be chosen for a single video, and channel tabs
+    // only apply to channels
+    pub fn check_applies_to(&self, kind: FetchType) -> Result<()> {
+        if kind == FetchType::Playlist && self.tab.is_some() {
+            return OptionNotApplicable { option: "--tab", what: "playlists" }.fail();
+        }
+        if kind != FetchType::Video {
+            return Ok(());
+        }
+        let option = if self.limit.is_some() {
+            "--limit"
+        } else if self.since.is_some() {
+            "--since"
+        } else if self.until.is_some() {
+            "--until"
+        } else if self.tab.is_some() {
+            "--tab"
+        } else {
+            return Ok(());
+        };
+        OptionNotApplicable { option, what: "single videos" }.fail()
+    }
+}
+
+fn parse_date(option: &str, value: &str) -> Result<String> {
+    static DATE_RE: &Lazy<Regex> = lazy_regex!(r"\A(\d{4})(-?)(0[1-9]|1[0-2])(-?)(0[1-9]|[12]\d|3[01])\z");
+    match DATE_RE.captures(value) {
+        // both dashes or neither
+        Some(captures) if captures[2] == captures[4] && is_valid_date(&captures[1], &captures[3], &captures[5]) => {
+            Ok(format!("{}{}{}", &captures[1], &captures[3], &captures[5]))
+        },
+        _ => InvalidOptionValue { option, value, expected: "a date like 2019-12-31" }.fail(),
+    }
+}
+
+// whether the day exists in that month, e.g. not 2019-02-29
+fn is_valid_date(year: &str, month: &str, day: &str) -> bool {
+    let (year, month, day) = match (year.parse::<u32>(), month.parse::<u32>(), day.parse::<u32>()) {
+        (Ok(year), Ok(month), Ok(day)) => (year, month, day),
+        _ => return false,
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    day <= days
+}
+
+// Parse the arguments of an archive command into its options and URL.
+// Options may appear before or after the URL, with values either as the
+// next argument or attached with `=`.
+pub fn parse_grab_command(args: &str) -> Result<(GrabOptions, &str)> {
+    let mut options = GrabOptions::default();
+    let mut urls = vec![];
+    let mut words = args.split_whitespace();
+    while let Some(word) = words.next() {
+        if !word.starts_with("--") {
+            urls.push(word);
+            continue;
+        }
+        let (name, attached) = match word.find('=') {
+            Some(idx) => (&word[..idx], Some(&word[idx + 1..])),
+            None => (word, None),
+        };
+        let spec = GRAB_OPTIONS.iter().find(|spec| spec.name == name)
+            .ok_or_else(|| Error::UnknownOption { option: name.to_string() })?;
+        let value = match spec.value {
+            None => {
+                ensure!(attached.is_none(), UnexpectedArguments { what: format!("{} takes no value", name) });
+                ""
+            },
+            Some(_) => match attached.or_else(|| words.next()) {
+                Some(value) => value,
+                None => return MissingOptionValue { option: name }.fail(),
+            },
+        };
+        match name {
+            "--profile" => options.profile = Some(value.to_string()),
+            "--big" => options.profile = Some("big".to_string()),
+            "--limit" => {
+                options.limit = match value.parse::<u32>() {
+                    Ok(limit) if limit > 0 => Some(limit),
+                    _ => return InvalidOptionValue { option: name, value, expected: "a positive number" }.fail(),
+                }
+            },
+            "--since" => options.since = Some(parse_date(name, value)?),
+            "--until" => options.until = Some(parse_date(name, value)?),
+            "--tab" => {
+                ensure!(CHANNEL_TABS.contains(&value), InvalidOptionValue { option: name, value, expected: "videos, streams or shorts" });
+                options.tab = Some(value.to_string());
+            },
+            _ => unreachable!(),
+        }
+    }
+    if let (Some(since), Some(until)) = (&options.since, &options.until) {
+        ensure!(since <= until, UnexpectedArguments { what: "--since is after --until".to_string() });
+    }
+    let url = match urls.as_slice() {
+        [url] => *url,
+        [] => return UnexpectedArguments { what: "missing URL".to_string() }.fail(),
+        _ => return UnexpectedArguments { what: "expected a single URL".to_string() }.fail(),
+    };
+    ensure!(url.len() <= 200, UrlTooLong);
+    Ok((options, url))
+}
+
+// `!help options` lists the options, `!help --name` describes one of them
+pub fn options_help(topic: &str) -> Option<String> {
+    if topic == "options" {
+        let names = GRAB_OPTIONS
+            .iter()
+            .map(|spec| match spec.value {
+                Some(value) => format!("{} {}", spec.name, value),
+                None => spec.name.to_string(),
+            })
+            .collect::<Vec<_>>();
+        return Some(format!("Options for !a and !sa: {}; see !help <option> for details", names.join(" | ")));
+    }
+    let spec = GRAB_OPTIONS.iter().find(|spec| spec.name == topic)?;
+    Some(match spec.value {
+        Some(value) => format!("{} {}: {}", spec.name, value, spec.help),
+        None => format!("{}: {}", spec.name, spec.help),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_grab_command() {
+        let (options, url) = parse_grab_command(" https://www.youtube.com/user/jblow888").unwrap();
+        assert_eq!(url, "https://www.youtube.com/user/jblow888");
+        assert_eq!(options, GrabOptions::default());
+
+        let (options, url) = parse_grab_command(
+            "--profile big --limit 10 https://www.youtube.com/user/jblow888 --since=2019-01-31 --until 20191231 --tab streams"
+        ).unwrap();
+        assert_eq!(url, "https://www.youtube.com/user/jblow888");
+        assert_eq!(options, GrabOptions {
+            profile: Some("big".to_string()),
+            limit: Some(10),
+            since: Some("20190131".to_string()),
+            until: Some("20191231".to_string()),
+            tab: Some("streams".to_string()),
+        });
+        assert_eq!(parse_date("--since", "2020-02-29").unwrap(), "20200229");
+        assert_eq!(options.extra_args(), vec!["--since", "20190131", "--until", "20191231", "--tab", "streams"]);
+    }
+
+    #[test]
+    fn test_parse_grab_command_errors() {
+        for (args, error) in [
+            ("--bigg URL", "Unknown option --bigg"),
+            ("URL --profile", "Missing value for --profile"),
+            ("URL --limit", "Missing value for --limit"),
+            ("--limit 0 URL", "Invalid value 0 for --limit, expected a positive number"),
+            ("--since 2019-13-01 URL", "Invalid value 2019-13-01 for --since, expected a date like 2019-12-31"),
+            ("--until 2020-02-31 URL", "Invalid value 2020-02-31 for --until, expected a date like 2019-12-31"),
+            ("--since 2019-02-29 URL", "Invalid value 2019-02-29 for --since, expected a date like 2019-12-31"),
+            ("--since 2019-1231 URL", "Invalid value 2019-1231 for --since, expected a date like 2019-12-31"),
+            ("--since 201912-31 URL", "Invalid value 201912-31 for --since, expected a date like 2019-12-31"),
+            ("--tab live URL", "Invalid value live for --tab, expected videos, streams or shorts"),
+            ("--big=yes URL", "Unexpected arguments: --big takes no value"),
+            ("--since 2020-01-01 --until 2019-01-01 URL", "Unexpected arguments: --since is after --until"),
+            ("--big", "Unexpected arguments: missing URL"),
+            ("URL URL", "Unexpected arguments: expected a single URL"),
+        ].iter() {
+            assert_eq!(parse_grab_command(args).unwrap_err().to_string(), *error);
+        }
+    }
+
+    #[test]
+    fn test_check_applies_to() {
+        let (options, _) = parse_grab_command("--big URL").unwrap();
+        assert_eq!(options.profile, Some("big".to_string()));
+        assert!(options.check_applies_to(FetchType::Video).is_ok());
+        let options = GrabOptions { tab: Some("streams".to_string()), ..GrabOptions::default() };
+        assert!(options.check_applies_to(FetchType::Channel).is_ok());
+        assert!(options.check_applies_to(FetchType::Video).is_err());
+        assert_eq!(
+            options.check_applies_to(FetchType::Playlist).unwrap_err().to_string(),
+            "--tab can't be used for playlists"
+        );
+    }
+
+    #[test]
+    fn test_options_help() {
+        assert_eq!(
+            options_help("--limit").unwrap(),
+            "--limit N: grab at most N videos from a channel or playlist"
+        );
+        assert!(options_help("options").unwrap().starts_with("Options for !a and !sa: --profile NAME | --big | --limit N |"));
+        assert!(options_help("--nope").is_none());
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * Runtime state
+ *
+ */
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use crate::config::RateLimit;
+use crate::message::{CanonicalizedYoutubeDescriptor, FetchType};
+use crate::options::GrabOptions;
+use crate::tmux::DownloaderSession;
+use crate::users::Users;
+
+// whose task limits apply to a request and who may control its task: the
+// services account when `key_by_account` is set and the requester is
+// logged in, their nick otherwise. Each network, by connection index, has
+// its own nicks and accounts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Identity {
+    Account(usize, String),
+    Nick(usize, String),
+}
+
+impl Identity {
+    pub fn name(&self) -> &str {
+        match self {
+            Identity::Account(_, name) | Identity::Nick(_, name) => name,
+        }
+    }
+
+    // an account never matches a nick, even if they are spelled the same,
+    // nor does anyone match someone on another network
+    pub fn matches(&self, other: &Identity) -> bool {
+        match (self, other) {
+            (Identity::Account(n, a), Identity::Account(m, b)) | (Identity::Nick(n, a), Identity::Nick(m, b)) => {
+                n == m && a.eq_ignore_ascii_case(b)
+            },
+            _ => false,
+        }
+    }
+}
+
+// a task started by the bot, keyed by its folder in `State::tasks`
+pub struct TaskRecord {
+    pub kind: FetchType,
+    pub requester: String,
+    // the trusted user who approved the request, if it needed approval
+    pub approver: Option<String>,
+    pub owner: Identity,
+    // the approver can control the task as well as its owner
+    pub approver_identity: Option<Identity>,
+    pub profile: String,
+    // where it was requested, for alerts about it
+    pub network: usize,
+    pub channel: Option<String>,
+}
+
+impl TaskRecord {
+    pub fn new(request: &GrabRequest, profile: String) -> Self {
+        Self {
+            kind: request.descriptor.kind(),
+            requester: request.requester.clone(),
+            approver: request.approver.clone(),
+            owner: request.owner.clone(),
+            approver_identity: request.approver_identity.clone(),
+            profile,
+            network: request.network,
+            channel: request.channel.clone(),
+        }
+    }
+}
+
+// an archive request, kept in the queue until it can be started and in the
+// history for `!retry`, or until it is approved if it needs approval
+#[derive(Clone)]
+pub struct GrabRequest {
+    pub url: String,
+    pub descriptor: CanonicalizedYoutubeDescriptor,
+    pub options: GrabOptions,
+    pub requester: String,
+    pub approver: Option<String>,
+    pub owner: Identity,
+    pub approver_identity: Option<Identity>,
+    // where it was requested: the index of the connection, and the channel
+    // or None for a private message
+    pub network: usize,
+    pub channel: Option<String>,
+}
+
+impl GrabRequest {
+    // approved requests count against the approver's task limit
+    pub fn limited_as(&self) -> &Identity {
+        self.approver_identity.as_ref().unwrap_or(&self.owner)
+    }
+}
+
+// what a task's stash looked like when it last changed, for the watchdog
+pub struct TaskProgress {
+    pub files: usize,
+    pub newest: Option<String>,
+    pub changed_at: u64,
+    pub alerted: bool,
+}
+
+// a bulk abort waiting for confirmation
+pub struct PendingAbort {
+    pub tasks: Vec<String>,
+    pub expires: u64,
+}
+
+// how a task being aborted ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortOutcome {
+    Interrupted,
+    Terminated,
+    Killed,
+    StillRunning,
+}
+
+// the last step taken to abort tasks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortStage {
+    Interrupt,
+    Terminate,
+    Kill,
+}
+
+// tasks being aborted together; `process_aborts` escalates them from a
+// timer rather than waiting for them to exit in the handler
+pub struct AbortJob {
+    pub running: Vec<String>,
+    pub outcomes: Vec<(String, AbortOutcome)>,
+    pub stage: AbortStage,
+    // unix time at which to escalate to the next stage
+    pub deadline: u64,
+    // who to tell the result, or None for every network's command channel
+    pub requester: Option<String>,
+    pub network: usize,
+    pub channel: Option<String>,
+    // put before the result, e.g. why the watchdog aborted the task
+    pub context: String,
+    // report how many tasks ended which way, rather than the single task
+    pub bulk: bool,
+}
+
+impl AbortJob {
+    pub fn new(tasks: Vec<String>, requester: Option<String>, network: usize, channel: Option<String>) -> Self {
+        Self {
+            running: tasks,
+            outcomes: vec![],
+            stage: AbortStage::Interrupt,
+            deadline: 0,
+            requester,
+            network,
+            channel,
+            context: String::new(),
+            bulk: false,
+        }
+    }
+}
+
+// commands a user can still send right away under a rate limit
+pub struct TokenBucket {
+    pub tokens: u32,
+    pub updated: u64,
+}
+
+impl TokenBucket {
+    pub fn new(limit: &RateLimit, now: u64) -> Self {
+        Self { tokens: limit.burst, updated: now }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: u64) {
+        let added = (now.saturating_sub(self.updated) / limit.interval.max(1)) as u32;
+        if self.tokens.saturating_add(added) >= limit.burst {
+            self.tokens = limit.burst;
+            self.updated = now;
+        } else {
+            self.tokens += added;
+            self.updated += added as u64 * limit.interval.max(1);
+        }
+    }
+
+    pub fn is_full(&mut self, limit: &RateLimit, now: u64) -> bool {
+        self.refill(limit, now);
+        self.tokens >= limit.burst
+    }
+
+    // take a token, or return how many seconds until there is one
+    pub fn take(&mut self, limit: &RateLimit, now: u64) -> Result<(), u64> {
+        self.refill(limit, now);
+        if self.tokens == 0 {
+            return Err((self.updated + limit.interval).saturating_sub(now));
+        }
+        self.tokens -= 1;
+        Ok(())
+    }
+}
+
+// mutable data kept for as long as the bot is running
+#[derive(Default)]
+pub struct State {
+    pub tasks: HashMap<String, TaskRecord>,
+    pub queue: VecDeque<GrabRequest>,
+    // latest request for each folder
+    pub history: HashMap<String, GrabRequest>,
+    pub progress: HashMap<String, TaskProgress>,
+    pub paused: HashSet<String>,
+    // keyed by the network and lowercased nick of the admin who has to confirm
+    pub pending_aborts: HashMap<(usize, String), PendingAbort>,
+    pub aborts: Vec<AbortJob>,
+    // what is known about users on each network, by connection index
+    pub users: Vec<Users>,
+    // requests waiting for approval, by their ID
+    pub pending: BTreeMap<u32, GrabRequest>,
+    pub next_pending_id: u32,
+    // keyed by network, lowercased identity name and whether the command
+    // is privileged
+    pub rate_limits: HashMap<(usize, String, bool), TokenBucket>,
+}
+
+impl State {
+    pub fn users_on(&mut self, network: usize) -> &mut Users {
+        if self.users.len() <= network {
+            self.users.resize_with(network + 1, Users::default);
+        }
+        &mut self.users[network]
+    }
+
+    // drop records of tasks whose downloader session has exited
+    pub fn forget_finished(&mut self, sessions: &[DownloaderSession]) {
+        let running = |folder: &String| sessions.iter().any(|session| &session.identifier == folder);
+        self.tasks.retain(|folder, _| running(folder));
+        self.progress.retain(|folder, _| running(folder));
+        self.paused.retain(|folder| running(folder));
+    }
+
+    pub fn is_aborting(&self, task: &str) -> bool {
+        self.aborts.iter().any(|job| job.running.iter().any(|running| running == task))
+    }
+
+    // number of running sessions that were started as single-video grabs
+    pub fn running_videos(&self, sessions: &[DownloaderSession]) -> usize {
+        sessions
+            .iter()
+            .filter(|session| {
+                matches!(self.tasks.get(&session.identifier), Some(task) if task.kind == FetchType::Video)
+            })
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limit = RateLimit { burst: 2, interval: 10 };
+        let mut bucket = TokenBucket::new(&limit, 100);
+        assert_eq!(bucket.take(&limit, 100), Ok(()));
+        assert_eq!(bucket.take(&limit, 101), Ok(()));
+        assert_eq!(bucket.take(&limit, 102), Err(8));
+        assert_eq!(bucket.take(&limit, 111), Ok(()));
+        assert_eq!(bucket.take(&limit, 111), Err(9));
+        assert!(!bucket.is_full(&limit, 125));
+        assert!(bucket.is_full(&limit, 131));
+    }
+}
@@ -0,0 +1,115 @@
+/*
+ * Downloader sessions, which the grab scripts run in tmux
+ *
+ */
+use std::process;
+use std::str;
+use snafu::ResultExt;
+use crate::message::{Result, Io, Utf8, TaskControlFailed};
+
+const SESSION_PREFIX: &str = "YouTube-";
+
+#[derive(Debug)]
+pub struct DownloaderSession {
+    pub identifier: String,
+    pub start_time: u64,
+}
+
+// tmux falls back to prefix matching for session names unless they start
+// with `=`, so `YouTube-foo` could otherwise target `YouTube-foobar`
+fn session_target(task: &str) -> String {
+    format!("={}{}", SESSION_PREFIX, task)
+}
+
+pub fn get_downloader_sessions() -> Result<Vec<DownloaderSession>> {
+    let output = process::Command::new("tmux")
+        .arg("list-sessions")
+        .arg("-F")
+        .arg("#{session_created} #S")
+        .output()
+        .context(Io)?;
+    let stdout_utf8 = str::from_utf8(&output.stdout).context(Utf8)?;
+    let sessions =
+        stdout_utf8.lines()
+            .filter_map(|line| {
+                let parts = line.splitn(2, ' ').collect::<Vec<&str>>();
+                let start_time   = parts.get(0).unwrap().parse::<u64>().unwrap();
+                let session_name = parts.get(1).unwrap();
+                if session_name.starts_with(SESSION_PREFIX) {
+                    let identifier = session_name.replacen(SESSION_PREFIX, "", 1);
+                    Some(DownloaderSession { identifier, start_time })
+                } else {
+                    None
+                }
+            }).collect();
+    Ok(sessions)
+}
+
+pub fn session_exists(task: &str) -> Result<bool> {
+    let status = process::Command::new("tmux")
+        .arg("has-session").arg("-t").arg(session_target(task))
+        .stderr(process::Stdio::null())
+        .status()
+        .context(Io)?;
+    Ok(status.success())
+}
+
+pub fn kill_session(task: &str) -> Result<()> {
+    let _output = process::Command::new("tmux")
+        .arg("kill-session").arg("-t").arg(session_target(task))
+        .output()
+        .context(Io)?;
+    Ok(())
+}
+
+pub fn send_keys(task: &str, keys: &str) -> Result<()> {
+    let _output = process::Command::new("tmux")
+        .arg("send-keys").arg("-t").arg(format!("{}:", session_target(task))).arg(keys)
+        .output()
+        .context(Io)?;
+    Ok(())
+}
+
+fn process_group_members(pgid: &str) -> Result<Vec<String>> {
+    let output = process::Command::new("pgrep").arg("-g").arg(pgid).output().context(Io)?;
+    let stdout_utf8 = str::from_utf8(&output.stdout).context(Utf8)?;
+    Ok(stdout_utf8.lines().map(String::from).collect())
+}
+
+// Send a signal (e.g. STOP or CONT) to the process group of every pane in
+// the task's session; the grab scripts lead their own process group.
+// tmux continues a pane's own process as soon as it stops, so STOP only goes
+// to the other members of the group, which leaves the script waiting on its
+// stopped children.
+pub fn signal(task: &str, signal: &str) -> Result<()> {
+    let output = process::Command::new("tmux")
+        .arg("list-panes").arg("-s").arg("-t").arg(session_target(task))
+        .arg("-F").arg("#{pane_pid}")
+        .output()
+        .context(Io)?;
+    let stdout_utf8 = str::from_utf8(&output.stdout).context(Utf8)?;
+    let pids = stdout_utf8.lines().filter(|pid| !pid.is_empty()).collect::<Vec<_>>();
+    let action = format!("send SIG{} to", signal);
+    if !output.status.success() || pids.is_empty() {
+        return TaskControlFailed { action, task }.fail();
+    }
+    let mut targets = vec![];
+    for pid in pids {
+        if signal == "STOP" {
+            targets.extend(process_group_members(pid)?.into_iter().filter(|member| member != pid));
+        } else {
+            targets.push(format!("-{}", pid));
+        }
+    }
+    if targets.is_empty() {
+        return TaskControlFailed { action, task }.fail();
+    }
+    let status = process::Command::new("kill")
+        .arg("-s").arg(signal).arg("--").args(&targets)
+        .status()
+        .context(Io)?;
+    if !status.success() {
+        return TaskControlFailed { action, task }.fail();
+    }
+    Ok(())
+}
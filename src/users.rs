@@ -0,0 +1,437 @@
+/*
+ * What the bot knows about other users on IRC
+ *
+ */
+use std::collections::{HashMap, HashSet};
+use irc::client::prelude::*;
+use irc::proto::CapSubCommand;
+
+// marks replies to our own WHOX queries
+const WHOX_TOKEN: &str = "837";
+
+// IRCv3 capabilities used to learn the services account of users
+pub const ACCOUNT_CAPABILITIES: [Capability; 3] = [
+    Capability::AccountNotify,
+    Capability::ExtendedJoin,
+    Capability::AccountTag,
+];
+
+// without multi-prefix, NAMES only lists the highest prefix of each nick, so
+// losing op would look like losing voice too. Requested separately, since a
+// server rejects a whole request if it lacks one of the capabilities.
+pub const PREFIX_CAPABILITIES: [Capability; 1] = [Capability::MultiPrefix];
+
+pub struct Users {
+    // services account by lowercased nick; None if known not to be logged in
+    accounts: HashMap<String, Option<String>>,
+    // capabilities acknowledged by the server
+    caps: HashSet<String>,
+    // whether the server supports WHOX, the fallback for extended-join
+    whox: bool,
+    // channel prefix modes and their symbols, highest first
+    prefixes: Vec<(char, char)>,
+    // list modes like b and, on some servers, q, from CHANMODES
+    list_modes: String,
+    // prefix symbols held by each lowercased nick, by lowercased channel
+    channels: HashMap<String, HashMap<String, String>>,
+    // nick!user@host the server relays our messages with, once we've seen it
+    own_prefix: Option<String>,
+}
+
+impl Default for Users {
+    fn default() -> Self {
+        Self {
+            accounts: HashMap::new(),
+            caps: HashSet::new(),
+            whox: false,
+            prefixes: vec![('o', '@'), ('v', '+')],
+            list_modes: "b".to_string(),
+            channels: HashMap::new(),
+            own_prefix: None,
+        }
+    }
+}
+
+fn whox_query(target: &str) -> Command {
+    Command::Raw("WHO".to_string(), vec![target.to_string(), format!("%tna,{}", WHOX_TOKEN)], None)
+}
+
+// PREFIX=(qaohv)~&@%+ from RPL_ISUPPORT
+fn parse_prefixes(value: &str) -> Option<Vec<(char, char)>> {
+    let value = value.strip_prefix('(')?;
+    let (modes, symbols) = value.split_at(value.find(')')?);
+    Some(modes.chars().zip(symbols[1..].chars()).collect())
+}
+
+// "*" (extended-join, ACCOUNT) and "0" (WHOX) mean not logged in
+fn parse_account(account: &str) -> Option<String> {
+    match account {
+        "*" | "0" => None,
+        account => Some(account.to_string()),
+    }
+}
+
+impl Users {
+    pub fn account(&self, nick: &str) -> Option<&str> {
+        self.accounts.get(&nick.to_lowercase()).and_then(|account| account.as_deref())
+    }
+
+    fn set_account(&mut self, nick: &str, account: Option<String>) {
+        self.accounts.insert(nick.to_lowercase(), account);
+    }
+
+    // Whether a nick holds the prefix mode with the given symbol in a
+    // channel, or a higher one
+    pub fn has_prefix(&self, channel: &str, nick: &str, required: char) -> bool {
+        let held = match self.channels.get(&channel.to_lowercase()).and_then(|nicks| nicks.get(&nick.to_lowercase())) {
+            Some(held) => held,
+            None => return false,
+        };
+        let rank = |symbol: char| self.prefixes.iter().position(|&(_, s)| s == symbol);
+        match rank(required) {
+            Some(required) => held.chars().filter_map(rank).any(|rank| rank <= required),
+            None => held.contains(required),
+        }
+    }
+
+    // whether a channel mode takes a mask list, such as the quiet list
+    // `+q` on charybdis and solanum, rather than being, say, the owner prefix
+    pub fn has_list_mode(&self, mode: char) -> bool {
+        self.list_modes.contains(mode)
+    }
+
+    pub fn own_prefix(&self) -> Option<&str> {
+        self.own_prefix.as_deref()
+    }
+
+    fn is_own_nick(&self, nick: &str) -> bool {
+        matches!(&self.own_prefix, Some(prefix) if prefix.split('!').next().unwrap_or("").eq_ignore_ascii_case(nick))
+    }
+
+    // replace the parts of our prefix that changed
+    fn update_own_prefix(&mut self, nick: Option<&str>, user: Option<&str>, host: Option<&str>) {
+        let prefix = match &self.own_prefix {
+            Some(prefix) => prefix,
+            None => return,
+        };
+        let (old_nick, mask) = prefix.split_once('!').unwrap_or((prefix, ""));
+        let (old_user, old_host) = mask.split_once('@').unwrap_or((mask, ""));
+        self.own_prefix = Some(format!("{}!{}@{}", nick.unwrap_or(old_nick), user.unwrap_or(old_user), host.unwrap_or(old_host)));
+    }
+
+    // nicks in a channel, lowercased
+    pub fn nicks<'a>(&'a self, channel: &str) -> impl Iterator<Item = &'a str> {
+        self.channels.get(&channel.to_lowercase()).into_iter().flat_map(|nicks| nicks.keys().map(String::as_str))
+    }
+
+    fn set_prefix(&mut self, channel: &str, nick: &str, mode: char, add: bool) {
+        let symbol = match self.prefixes.iter().find(|&&(m, _)| m == mode) {
+            Some(&(_, symbol)) => symbol,
+            None => return,
+        };
+        let held = self.channels
+            .get_mut(&channel.to_lowercase())
+            .and_then(|nicks| nicks.get_mut(&nick.to_lowercase()));
+        if let Some(held) = held {
+            held.retain(|s| s != symbol);
+            if add {
+                held.push(symbol);
+            }
+        }
+    }
+
+    fn join(&mut self, channel: &str, nick: &str, symbols: &str) {
+        if let Some(nicks) = self.channels.get_mut(&channel.to_lowercase()) {
+            nicks.insert(nick.to_lowercase(), symbols.to_string());
+        }
+    }
+
+    fn part(&mut self, channel: &str, nick: &str, own_nick: &str) {
+        if nick.eq_ignore_ascii_case(own_nick) {
+            self.channels.remove(&channel.to_lowercase());
+        } else if let Some(nicks) = self.channels.get_mut(&channel.to_lowercase()) {
+            nicks.remove(&nick.to_lowercase());
+        }
+        // we no longer hear when users who share no channel with us log out
+        // or change nick, so someone else could take the nick with their account
+        let channels = &self.channels;
+        self.accounts.retain(|nick, _| channels.values().any(|nicks| nicks.contains_key(nick)));
+    }
+
+    // Update from a message received from the server, returning a WHOX
+    // query to send if accounts have to be looked up
+    pub fn update(&mut self, message: &Message, own_nick: &str) -> Option<Command> {
+        let nick = message.source_nickname();
+        let tag = message.tags
+            .iter()
+            .flatten()
+            .find(|tag| tag.0 == "account")
+            .and_then(|tag| tag.1.clone());
+        // with account-tag, a message without the tag is from a user who
+        // isn't logged in
+        let from_user = matches!(&message.prefix, Some(prefix) if prefix.contains('!'));
+        match (nick, tag) {
+            (Some(nick), Some(account)) => self.set_account(nick, Some(account)),
+            (Some(nick), None) if from_user && self.caps.contains("account-tag") => self.set_account(nick, None),
+            _ => (),
+        }
+        match &message.command {
+            Command::CAP(_, CapSubCommand::ACK, arg, suffix) => {
+                if let Some(caps) = suffix.as_ref().or(arg.as_ref()) {
+                    self.caps.extend(caps.split_whitespace().map(String::from));
+                }
+            },
+            Command::Response(Response::RPL_ISUPPORT, args, _) => {
+                for arg in args {
+                    if arg == "WHOX" {
+                        self.whox = true;
+                    } else if let Some(prefixes) = arg.strip_prefix("PREFIX=").and_then(parse_prefixes) {
+                        self.prefixes = prefixes;
+                    } else if let Some(modes) = arg.strip_prefix("CHANMODES=") {
+                        self.list_modes = modes.split(',').next().unwrap_or("").to_string();
+                    }
+                }
+            },
+            // <own nick> <type> <channel> :<names>
+            Command::Response(Response::RPL_NAMREPLY, args, Some(names)) if args.len() >= 3 => {
+                for name in names.split_whitespace() {
+                    let nick = name.trim_start_matches(|c| self.prefixes.iter().any(|&(_, s)| s == c));
+                    let symbols = &name[..name.len() - nick.len()];
+                    self.join(&args[2], nick, symbols);
+                }
+            },
+            Command::JOIN(channel, account, real_name) => {
+                let nick = nick?;
+                if nick.eq_ignore_ascii_case(own_nick) {
+                    self.own_prefix = message.prefix.clone();
+                    self.channels.insert(channel.to_lowercase(), HashMap::new());
+                    // learn the accounts of everyone already in the channel
+                    return if self.whox { Some(whox_query(channel)) } else { None };
+                }
+                self.join(channel, nick, "");
+                match (account, real_name, self.caps.contains("extended-join")) {
+                    (Some(account), Some(_), true) => self.set_account(nick, parse_account(account)),
+                    _ if self.whox => return Some(whox_query(nick)),
+                    _ => (),
+                }
+            },
+            Command::ACCOUNT(account) => {
+                self.set_account(nick?, parse_account(account));
+            },
+            Command::PART(channel, _) => {
+                self.part(channel, nick?, own_nick);
+            },
+            Command::KICK(channel, target, _) => {
+                self.part(channel, target, own_nick);
+            },
+            Command::ChannelMODE(channel, modes) => {
+                // the irc crate keeps the first sign for a whole mode string
+                // like "-o+v", parsing later signs as unknown modes
+                let mut sign = None;
+                for mode in modes {
+                    let (mode, target, add) = match mode {
+                        Mode::Plus(mode, target) => (mode, target, true),
+                        Mode::Minus(mode, target) => (mode, target, false),
+                    };
+                    match (mode, target) {
+                        (ChannelMode::Unknown('+'), _) => sign = Some(true),
+                        (ChannelMode::Unknown('-'), _) => sign = Some(false),
+                        (mode, Some(target)) => {
+                            if let Some(mode) = mode.to_string().chars().next() {
+                                self.set_prefix(channel, target, mode, sign.unwrap_or(add));
+                            }
+                        },
+                        _ => (),
+                    }
+                }
+            },
+            Command::NICK(new_nick) => {
+                let nick = nick?;
+                if self.is_own_nick(nick) {
+                    self.update_own_prefix(Some(new_nick), None, None);
+                }
+                let nick = nick.to_lowercase();
+                if let Some(account) = self.accounts.remove(&nick) {
+                    self.accounts.insert(new_nick.to_lowercase(), account);
+                }
+                for nicks in self.channels.values_mut() {
+                    if let Some(symbols) = nicks.remove(&nick) {
+                        nicks.insert(new_nick.to_lowercase(), symbols);
+                    }
+                }
+            },
+            Command::CHGHOST(user, host) if matches!(nick, Some(nick) if self.is_own_nick(nick)) => {
+                self.update_own_prefix(None, Some(user), Some(host));
+            },
+            // <own nick> <host> :is now your displayed host
+            Command::Response(Response::RPL_HOSTHIDDEN, args, _) if args.len() >= 2 => {
+                self.update_own_prefix(None, None, Some(&args[1]));
+            },
+            Command::QUIT(_) => {
+                let nick = nick?.to_lowercase();
+                self.accounts.remove(&nick);
+                for nicks in self.channels.values_mut() {
+                    nicks.remove(&nick);
+                }
+            },
+            // <own nick> <token> <nick> <account>
+            Command::Raw(code, args, suffix) if code == "354" => {
+                let mut fields = args.iter().chain(suffix.iter()).skip(1);
+                if fields.next().map(String::as_str) == Some(WHOX_TOKEN) {
+                    if let (Some(nick), Some(account)) = (fields.next(), fields.next()) {
+                        self.set_account(nick, parse_account(account));
+                    }
+                }
+            },
+            _ => (),
+        }
+        None
+    }
+
+    pub fn track(&mut self, client: &IrcClient, message: &Message) {
+        if let Some(query) = self.update(message, client.current_nickname()) {
+            let _ = client.send(query);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(users: &mut Users, line: &str) -> Option<String> {
+        let message = line.parse::<Message>().unwrap();
+        users.update(&message, "bot").map(|command| Message::from(command).to_string())
+    }
+
+    #[test]
+    fn test_extended_join_and_account_notify() {
+        let mut users = Users::default();
+        update(&mut users, ":server CAP * ACK :account-notify extended-join");
+        update(&mut users, ":alice!a@host JOIN #chan alice_acct :Alice");
+        update(&mut users, ":bob!b@host JOIN #chan * :Bob");
+        assert_eq!(users.account("Alice"), Some("alice_acct"));
+        assert_eq!(users.account("bob"), None);
+
+        update(&mut users, ":bob!b@host ACCOUNT bob_acct");
+        update(&mut users, ":alice!a@host ACCOUNT *");
+        assert_eq!(users.account("bob"), Some("bob_acct"));
+        assert_eq!(users.account("alice"), None);
+
+        update(&mut users, ":bob!b@host NICK robert");
+        assert_eq!(users.account("bob"), None);
+        assert_eq!(users.account("robert"), Some("bob_acct"));
+        update(&mut users, ":robert!b@host QUIT :bye");
+        assert_eq!(users.account("robert"), None);
+    }
+
+    #[test]
+    fn test_part_forgets_account() {
+        let mut users = Users::default();
+        update(&mut users, ":server CAP * ACK :account-notify extended-join");
+        update(&mut users, ":bot!b@host JOIN #one");
+        update(&mut users, ":bot!b@host JOIN #two");
+        update(&mut users, ":alice!a@host JOIN #one alice_acct :Alice");
+        update(&mut users, ":alice!a@host JOIN #two alice_acct :Alice");
+        update(&mut users, ":bob!b@host JOIN #one bob_acct :Bob");
+        update(&mut users, ":alice!a@host PART #one");
+        assert_eq!(users.account("alice"), Some("alice_acct"));
+        update(&mut users, ":op!o@host KICK #two alice :bye");
+        assert_eq!(users.account("alice"), None);
+        update(&mut users, ":bot!b@host PART #one");
+        assert_eq!(users.account("bob"), None);
+    }
+
+    #[test]
+    fn test_account_tag() {
+        let mut users = Users::default();
+        update(&mut users, "@account=carol_acct :carol!c@host PRIVMSG #chan :!status");
+        assert_eq!(users.account("carol"), Some("carol_acct"));
+        update(&mut users, ":carol!c@host PRIVMSG #chan :!status");
+        assert_eq!(users.account("carol"), Some("carol_acct"));
+
+        update(&mut users, ":server CAP * ACK :account-tag");
+        update(&mut users, ":carol!c@host PRIVMSG #chan :!status");
+        assert_eq!(users.account("carol"), None);
+    }
+
+    #[test]
+    fn test_channel_prefixes() {
+        let mut users = Users::default();
+        update(&mut users, ":bot!b@host JOIN #chan");
+        update(&mut users, ":server 353 bot = #chan :@alice +bob carol bot");
+        assert!(users.has_prefix("#chan", "alice", '+'));
+        assert!(users.has_prefix("#Chan", "Bob", '+'));
+        assert!(!users.has_prefix("#chan", "bob", '@'));
+        assert!(!users.has_prefix("#chan", "carol", '+'));
+        assert!(!users.has_prefix("#other", "alice", '+'));
+
+        update(&mut users, ":alice!a@host MODE #chan -o+v alice carol");
+        update(&mut users, ":carol!c@host NICK caroline");
+        assert!(!users.has_prefix("#chan", "alice", '+'));
+        assert!(users.has_prefix("#chan", "caroline", '+'));
+
+        update(&mut users, ":dave!d@host JOIN #chan");
+        update(&mut users, ":bob!b@host PART #chan");
+        update(&mut users, ":op!o@host KICK #chan caroline :bye");
+        update(&mut users, ":op!o@host MODE #chan +v dave");
+        assert!(!users.has_prefix("#chan", "bob", '+'));
+        assert!(!users.has_prefix("#chan", "caroline", '+'));
+        assert!(users.has_prefix("#chan", "dave", '+'));
+    }
+
+    #[test]
+    fn test_multi_prefix() {
+        let mut users = Users::default();
+        update(&mut users, ":bot!b@host JOIN #chan");
+        update(&mut users, ":server 353 bot = #chan :@+alice");
+        update(&mut users, ":op!o@host MODE #chan -o alice");
+        assert!(!users.has_prefix("#chan", "alice", '@'));
+        assert!(users.has_prefix("#chan", "alice", '+'));
+    }
+
+    #[test]
+    fn test_isupport_prefix() {
+        let mut users = Users::default();
+        update(&mut users, ":server 005 bot PREFIX=(qaohv)~&@%+ :are supported by this server");
+        update(&mut users, ":bot!b@host JOIN #chan");
+        update(&mut users, ":server 353 bot = #chan :~founder %halfop");
+        assert!(users.has_prefix("#chan", "founder", '@'));
+        assert!(users.has_prefix("#chan", "halfop", '+'));
+        assert!(!users.has_prefix("#chan", "halfop", '@'));
+        assert!(!users.has_list_mode('q'));
+        update(&mut users, ":server 005 bot CHANMODES=eIbq,k,flj,CFLMPQScgimnprstz :are supported by this server");
+        assert!(users.has_list_mode('q'));
+    }
+
+    #[test]
+    fn test_own_prefix() {
+        let mut users = Users::default();
+        assert_eq!(users.own_prefix(), None);
+        update(&mut users, ":bot!b@host JOIN #chan");
+        assert_eq!(users.own_prefix(), Some("bot!b@host"));
+        update(&mut users, ":bot!b@host NICK botnick");
+        update(&mut users, ":alice!a@host NICK alicia");
+        assert_eq!(users.own_prefix(), Some("botnick!b@host"));
+        update(&mut users, ":server 396 botnick user/bot :is now your displayed host");
+        assert_eq!(users.own_prefix(), Some("botnick!b@user/bot"));
+        update(&mut users, ":botnick!b@user/bot CHGHOST ~bot bot.example");
+        update(&mut users, ":alicia!a@host CHGHOST a elsewhere");
+        assert_eq!(users.own_prefix(), Some("botnick!~bot@bot.example"));
+    }
+
+    #[test]
+    fn test_whox_fallback() {
+        let mut users = Users::default();
+        assert_eq!(update(&mut users, ":bot!b@host JOIN #chan"), None);
+        update(&mut users, ":server 005 bot WHOX NETWORK=Test :are supported by this server");
+        assert_eq!(update(&mut users, ":bot!b@host JOIN #chan"), Some("WHO #chan %tna,837\r\n".to_string()));
+        assert_eq!(update(&mut users, ":dave!d@host JOIN #chan"), Some("WHO dave %tna,837\r\n".to_string()));
+        update(&mut users, ":server 354 bot 837 dave dave_acct");
+        update(&mut users, ":server 354 bot 837 erin 0");
+        update(&mut users, ":server 354 bot 999 frank frank_acct");
+        assert_eq!(users.account("dave"), Some("dave_acct"));
+        assert_eq!(users.account("erin"), None);
+        assert_eq!(users.account("frank"), None);
+    }
+}